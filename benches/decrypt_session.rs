@@ -0,0 +1,32 @@
+// Many small messages under one key, where the per call AES key schedule of decrypt_data dominates.
+// Run with `cargo bench --bench decrypt_session`
+use crypt_guard_mceliece::{envelope, DecryptSession};
+use std::{hint::black_box, time::Instant};
+
+const MESSAGES: usize = 200_000;
+
+fn main() {
+    let key = [7u8; 32];
+    let hmac_key = [9u8; 64];
+    // Decryption works on any whole number of blocks, so arbitrary bytes stand in for ciphertext
+    let message = [0x5au8; 64];
+
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        black_box(envelope::decrypt_data(black_box(&message), &key).unwrap());
+    }
+    let per_call = start.elapsed();
+
+    let session = DecryptSession::new(&key, &hmac_key).unwrap();
+    let mut out = Vec::new();
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        session.decrypt_data_into(black_box(&message), &mut out).unwrap();
+        black_box(&out);
+    }
+    let cached = start.elapsed();
+
+    println!("decrypt_data:   {} key schedules, {:?}", MESSAGES, per_call);
+    println!("DecryptSession: 1 key schedule, {:?}", cached);
+    println!("speedup:        {:.2}x", per_call.as_secs_f64() / cached.as_secs_f64());
+}
@@ -0,0 +1,160 @@
+use crate::keychain::*;
+use crate::{
+    envelope,
+    DecryptMceliece as Decrypt,
+    EncryptMceliece as Encrypt,
+};
+use pqcrypto_traits::kem::SharedSecret;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+// Member layout: name length (u32), name, plaintext length (u64), encrypted length (u64), encrypted member.
+// Each member's HMAC also covers its name length, name and plaintext length as associated data, and a changed
+// encrypted length shifts the tag, so a member is authenticated on its own before it is returned.
+// A zero name length ends the archive and is followed by the member count (u64) and the index HMAC.
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    key: Vec<u8>,
+    hmac_key: Vec<u8>,
+    index: Hmac<Sha512>,
+    members: u64,
+}
+
+pub struct ArchiveReader<R: Read> {
+    reader: R,
+    key: Vec<u8>,
+    hmac_key: Vec<u8>,
+    index: Hmac<Sha512>,
+    finished: bool,
+}
+
+fn new_index(hmac_key: &[u8]) -> Hmac<Sha512> {
+    <Hmac<Sha512> as Mac>::new_from_slice(hmac_key)
+        .expect("HMAC can take key of any size")
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W, shared_secret: &dyn SharedSecret, hmac_key: &[u8]) -> Self {
+        Self {
+            writer,
+            key: shared_secret.as_bytes().to_vec(),
+            hmac_key: hmac_key.to_vec(),
+            index: new_index(hmac_key),
+            members: 0,
+        }
+    }
+
+    pub async fn add_file(&mut self, name: &str, mut reader: impl Read) -> Result<(), CryptError> {
+        if name.is_empty() {
+            return Err(CryptError::InvalidParameters);
+        }
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let mut header = vec![];
+        header.write_u32::<BigEndian>(name.len() as u32).unwrap();
+        header.extend_from_slice(name.as_bytes());
+        header.write_u64::<BigEndian>(data.len() as u64).unwrap();
+        let encrypted_data = Encrypt::new().encrypt_data_with_aad(&data, &self.key, &self.hmac_key, &header).await?;
+        header.write_u64::<BigEndian>(encrypted_data.len() as u64).unwrap();
+
+        // The index binds every member's header and tag, so reordering or dropping members is detected
+        self.index.update(&header);
+        self.index.update(&encrypted_data[encrypted_data.len() - 64..]);
+
+        self.writer.write_all(&header).map_err(|_| CryptError::WriteError)?;
+        self.writer.write_all(&encrypted_data).map_err(|_| CryptError::WriteError)?;
+        self.members += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, CryptError> {
+        let mut trailer = vec![];
+        trailer.write_u32::<BigEndian>(0).unwrap();
+        trailer.write_u64::<BigEndian>(self.members).unwrap();
+        self.index.update(&trailer);
+        let index_hmac = self.index.finalize().into_bytes();
+
+        self.writer.write_all(&trailer).map_err(|_| CryptError::WriteError)?;
+        self.writer.write_all(&index_hmac).map_err(|_| CryptError::WriteError)?;
+        self.writer.flush().map_err(|_| CryptError::WriteError)?;
+        Ok(self.writer)
+    }
+}
+
+// Lengths come from the unauthenticated member header, so the buffer only grows with what the input actually holds
+fn read_len(reader: &mut impl Read, len: u64) -> Result<Vec<u8>, CryptError> {
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data).map_err(|_| CryptError::InvalidMessageFormat)?;
+    if data.len() as u64 != len {
+        return Err(CryptError::InvalidMessageFormat);
+    }
+    Ok(data)
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(reader: R, shared_secret: &dyn SharedSecret, hmac_key: &[u8]) -> Self {
+        Self {
+            reader,
+            key: shared_secret.as_bytes().to_vec(),
+            hmac_key: hmac_key.to_vec(),
+            index: new_index(hmac_key),
+            finished: false,
+        }
+    }
+
+    pub async fn next_member(&mut self) -> Result<Option<(String, Vec<u8>)>, CryptError> {
+        if self.finished {
+            return Ok(None);
+        }
+        check_distinct_keys(&self.key, &self.hmac_key)?;
+
+        let name_len = self.reader.read_u32::<BigEndian>().map_err(|_| CryptError::InvalidMessageFormat)?;
+        if name_len == 0 {
+            let members = self.reader.read_u64::<BigEndian>().map_err(|_| CryptError::InvalidMessageFormat)?;
+            let mut index_hmac = [0u8; 64];
+            self.reader.read_exact(&mut index_hmac).map_err(|_| CryptError::InvalidMessageFormat)?;
+
+            let mut trailer = vec![];
+            trailer.write_u32::<BigEndian>(0).unwrap();
+            trailer.write_u64::<BigEndian>(members).unwrap();
+            self.index.update(&trailer);
+            self.index.clone().verify_slice(&index_hmac).map_err(|_| CryptError::HmacVerificationError)?;
+
+            self.finished = true;
+            return Ok(None);
+        }
+
+        let name = read_len(&mut self.reader, name_len as u64)?;
+        let data_len = self.reader.read_u64::<BigEndian>().map_err(|_| CryptError::InvalidMessageFormat)?;
+        let encrypted_len = self.reader.read_u64::<BigEndian>().map_err(|_| CryptError::InvalidMessageFormat)?;
+        // The padded ciphertext is never shorter than the member, so data_len can't point past it
+        if encrypted_len < 64 || data_len > encrypted_len - 64 {
+            return Err(CryptError::InvalidMessageFormat);
+        }
+        let encrypted_data = read_len(&mut self.reader, encrypted_len)?;
+
+        let mut header = vec![];
+        header.write_u32::<BigEndian>(name_len).unwrap();
+        header.extend_from_slice(&name);
+        header.write_u64::<BigEndian>(data_len).unwrap();
+        let data = envelope::verify_hmac_with_aad(&self.hmac_key, &encrypted_data, &header, 64)?;
+        header.write_u64::<BigEndian>(encrypted_len).unwrap();
+        self.index.update(&header);
+        self.index.update(&encrypted_data[encrypted_data.len() - 64..]);
+
+        let mut data = Decrypt::new().decrypt_data(&data, &self.key).await?;
+
+        // decrypt_data strips trailing zero padding, which may also strip trailing zeros of the member itself
+        if data.len() as u64 > data_len {
+            return Err(CryptError::InvalidMessageFormat);
+        }
+        data.resize(data_len as usize, 0);
+
+        let name = String::from_utf8(name).map_err(|_| CryptError::Utf8Error)?;
+        Ok(Some((name, data)))
+    }
+}
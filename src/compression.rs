@@ -0,0 +1,60 @@
+use crate::error::CryptError;
+
+// With the compress feature files are gzipped before encryption and flagged as such in the header. The AES
+// path strips trailing zeros as padding, which would also eat zeros at the end of the gzip trailer, so a
+// non-zero end marker follows the stream
+#[cfg(feature = "compress")]
+const END_MARKER: u8 = 1;
+
+// Returns the data to encrypt and whether it was compressed, data that doesn't shrink is left as it is
+#[cfg(feature = "compress")]
+pub(crate) fn compress_file_data(data: Vec<u8>) -> Result<(Vec<u8>, bool), CryptError> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+    encoder.write_all(&data)?;
+    let mut compressed = encoder.finish()?;
+    compressed.push(END_MARKER);
+    if compressed.len() >= data.len() {
+        return Ok((data, false));
+    }
+    Ok((compressed, true))
+}
+
+#[cfg(not(feature = "compress"))]
+pub(crate) fn compress_file_data(data: Vec<u8>) -> Result<(Vec<u8>, bool), CryptError> {
+    Ok((data, false))
+}
+
+// The output is capped at limit bytes like the encrypted file itself, a small payload can otherwise expand
+// without bound
+#[cfg(feature = "compress")]
+pub(crate) fn decompress_file_data(compressed: bool, data: Vec<u8>, limit: Option<u64>) -> Result<Vec<u8>, CryptError> {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    if !compressed {
+        return Ok(data);
+    }
+    let stream = match data.split_last() {
+        Some((&END_MARKER, stream)) => stream,
+        _ => return Err(CryptError::InvalidMessageFormat),
+    };
+    // One byte past the limit is enough to tell that it was exceeded
+    let read_limit = limit.map_or(u64::MAX, |limit| limit.saturating_add(1));
+    let mut decompressed = Vec::new();
+    GzDecoder::new(stream).take(read_limit).read_to_end(&mut decompressed).map_err(|_| CryptError::InvalidMessageFormat)?;
+    if let Some(limit) = limit.filter(|&limit| decompressed.len() as u64 > limit) {
+        return Err(CryptError::FileTooLarge { size: decompressed.len() as u64, limit });
+    }
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "compress"))]
+pub(crate) fn decompress_file_data(compressed: bool, data: Vec<u8>, _limit: Option<u64>) -> Result<Vec<u8>, CryptError> {
+    if compressed {
+        return Err(CryptError::NotImplemented("compress feature not enabled"));
+    }
+    Ok(data)
+}
@@ -4,38 +4,60 @@ use pqcrypto_falcon::falcon1024::{self, *};
 use pqcrypto_traits::kem::{SharedSecret};
 use hmac::{Hmac, Mac};
 use sha2::Sha512;
+use zeroize::Zeroizing;
 use std::{
     str,
     fs::{self, File}, 
     path::{PathBuf, Path},
-    io::{self, Cursor, Read, Write},
-    env::current_dir
+    io::{self, Read, Write},
+    env::current_dir,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use crate::{
+    compression,
+    envelope::{self, DecryptSession, TagPosition},
+    framing,
+    header::{self, FileHeader},
+    kem::RuntimeKeychain,
     ActionTypeMceliece as ActionType,
+    CipherModeMceliece as CipherMode,
+    DecryptBuilderMceliece as DecryptBuilder,
     DecryptMceliece as Decrypt,
+    EncryptMceliece as Encrypt,
     KeychainMceliece as Keychain, 
+    EmbeddedSignatureMceliece as EmbeddedSignature,
+    SignatureAlgorithmMceliece as SignatureAlgorithm,
 };
 use pqcrypto_traits::sign::{
     DetachedSignature as DetachedSignatureSign, PublicKey as PublicKeySign,
     SecretKey as SecretKeySign, SignedMessage as SignedMessageSign,
 };
- use crypt_guard_sign::{self, *};
 
 #[cfg(feature = "xchacha20")]
 use chacha20::{
     XChaCha20, 
     cipher::{KeyIvInit, StreamCipher, StreamCipherSeek}
 };
+#[cfg(feature = "ctr")]
+use ctr::cipher::{KeyIvInit as _, StreamCipher as _, StreamCipherSeek as _};
+#[cfg(feature = "ctr")]
+use crate::encrypt::{Aes256Ctr, CTR_NONCE_LEN};
+#[cfg(feature = "tar")]
+use crate::encrypt::ARCHIVE_END_MARKER;
 use std::iter::repeat;
-use byteorder::{BigEndian, ReadBytesExt};
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "mmap")]
+use std::io::BufWriter;
 
 #[cfg(feature = "default")]
 use aes::{
     cipher::{
         self,
-        BlockDecrypt, 
         generic_array::GenericArray,
         KeyInit
     },
@@ -43,18 +65,258 @@ use aes::{
 };
 
 #[cfg(feature = "dilithium")]
-use crate::sign_dilithium::{self};
+use pqcrypto_dilithium::dilithium5;
+
+#[cfg(feature = "default")]
+const PROGRESS_CHUNK_SIZE: usize = 64 * 1024;
+
+// Falcon signatures vary in length up to signature_bytes, but always hold the header byte and the 40 byte nonce
+const FALCON_SIGNATURE_MIN_LEN: usize = 41;
+
+fn check_falcon_signature_len(signature: &[u8]) -> Result<(), CryptError> {
+    if !(FALCON_SIGNATURE_MIN_LEN..=falcon1024::signature_bytes()).contains(&signature.len()) {
+        return Err(CryptError::InvalidSignatureLength { expected: falcon1024::signature_bytes(), got: signature.len() });
+    }
+    Ok(())
+}
+
+// Version 3 XChaCha20 headers record the nonce, one passed in as well has to agree with it. Older files
+// only have the one passed in
+fn file_nonce<'a>(file_header: &FileHeader<'a>, nonce: Option<&'a [u8; 24]>) -> Result<&'a [u8; 24], CryptError> {
+    match (file_header.nonce, nonce) {
+        (Some(recorded), Some(nonce)) if recorded != nonce => Err(CryptError::InvalidParameters),
+        (Some(nonce), _) | (None, Some(nonce)) => Ok(nonce),
+        (None, None) => Err(CryptError::InvalidParameters),
+    }
+}
 
 fn find_subarray(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     haystack.windows(needle.len()).position(|window| window == needle)
 }
 
+impl DecryptSession {
+    // Session under the keys DerivedKeys expands from a KEM shared secret
+    pub fn from_shared_secret(shared_secret: &dyn SharedSecret) -> Result<Self, CryptError> {
+        let keys = DerivedKeys::from_shared_secret(shared_secret);
+        Self::new(&keys.aes_key, &keys.hmac_key)
+    }
+}
+
+impl Default for DecryptBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecryptBuilder {
+    pub fn new() -> Self {
+        Self {
+            mode: CipherMode::Aes,
+            hmac_len: envelope::HMAC_LEN,
+            write_output: true,
+            message_path: PathBuf::from("./message.txt"),
+            max_size: None,
+            tag_position: TagPosition::Suffix,
+            overwrite: true,
+        }
+    }
+
+    pub fn mode(mut self, mode: CipherMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn hmac_len(mut self, hmac_len: usize) -> Self {
+        self.hmac_len = hmac_len;
+        self
+    }
+
+    pub fn write_output(mut self, write_output: bool) -> Self {
+        self.write_output = write_output;
+        self
+    }
+
+    // Where decrypted messages are written while write_output is set, ./message.txt by default
+    pub fn message_path(mut self, path: PathBuf) -> Self {
+        self.message_path = path;
+        self
+    }
+
+    // Encrypted files above the limit are refused before they are read into memory
+    pub fn max_size(mut self, limit: u64) -> Self {
+        self.max_size = Some(limit);
+        self
+    }
+
+    // For interop with tools that put the HMAC in front of the ciphertext
+    pub fn tag_position(mut self, position: TagPosition) -> Self {
+        self.tag_position = position;
+        self
+    }
+
+    // With overwrite disabled an existing output file is reported as OutputExists instead of being replaced
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    pub fn build(self) -> Result<Decrypt, CryptError> {
+        // Tags may be truncated, but not below 128 bits
+        if !(envelope::MIN_TRUNCATED_HMAC_LEN..=envelope::HMAC_LEN).contains(&self.hmac_len) {
+            return Err(CryptError::InvalidParameters);
+        }
+        Ok(Decrypt {
+            mode: self.mode,
+            hmac_len: self.hmac_len,
+            write_output: self.write_output,
+            message_path: self.message_path,
+            max_size: self.max_size,
+            tag_position: self.tag_position,
+            overwrite: self.overwrite,
+        })
+    }
+}
+
 impl Decrypt {
     pub fn new() -> Self {
-        Self
+        Self {
+            mode: CipherMode::Aes,
+            hmac_len: envelope::HMAC_LEN,
+            write_output: true,
+            message_path: PathBuf::from("./message.txt"),
+            max_size: None,
+            tag_position: TagPosition::Suffix,
+            overwrite: true,
+        }
+    }
+
+    pub fn builder() -> DecryptBuilder {
+        DecryptBuilder::new()
+    }
+
+    fn check_file_size(&self, size: u64) -> Result<(), CryptError> {
+        match self.max_size {
+            Some(limit) if size > limit => Err(CryptError::FileTooLarge { size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_encrypted_file(&self, encrypted_file_path: impl AsRef<Path>) -> Result<Vec<u8>, CryptError> {
+        let mut file = File::open(encrypted_file_path.as_ref())?;
+        let Some(limit) = self.max_size else {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            return Ok(data);
+        };
+
+        // The file is checked and read through the same handle, and the read stops one byte past the limit
+        // in case it grew after the check
+        self.check_file_size(file.metadata()?.len())?;
+        let mut data = Vec::new();
+        file.take(limit.saturating_add(1)).read_to_end(&mut data)?;
+        self.check_file_size(data.len() as u64)?;
+        Ok(data)
+    }
+
+    // Without overwrite the output is created exclusively, so an existing file is never clobbered
+    fn create_output_file(&self, path: &Path) -> Result<File, CryptError> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true);
+        if self.overwrite {
+            options.create(true).truncate(true);
+        } else {
+            options.create_new(true);
+        }
+        options.open(path).map_err(|err| match err.kind() {
+            io::ErrorKind::AlreadyExists => CryptError::OutputExists(path.to_path_buf()),
+            _ => CryptError::WriteError,
+        })
+    }
+
+    fn write_decrypted_file(&self, path: impl AsRef<Path>, data: &[u8]) -> Result<(), CryptError> {
+        let mut output = self.create_output_file(path.as_ref())?;
+        output.write_all(data).map_err(|_| CryptError::WriteError)
+    }
+
+    // max_size bounds the decompressed plaintext as well
+    fn decompress(&self, compressed: bool, data: Vec<u8>) -> Result<Vec<u8>, CryptError> {
+        compression::decompress_file_data(compressed, data, self.max_size)
+    }
+
+    // Parses the header and verifies the tag before anything taken from the header, like the extension of the
+    // output path, is used. Version 3 headers are authenticated along with the ciphertext, older ones are only parsed
+    fn open_file<'a>(&self, data: &'a [u8], hmac_key: &[u8]) -> Result<(FileHeader<'a>, Vec<u8>), CryptError> {
+        let (file_header, data_with_hmac) = header::read_file_header(data)?;
+        let encrypted_data = match file_header.authenticated {
+            Some(header) => envelope::verify_hmac_with_aad_at(hmac_key, data_with_hmac, header, envelope::HMAC_LEN, self.tag_position)?,
+            None => envelope::verify_hmac_at(hmac_key, data_with_hmac, envelope::HMAC_LEN, self.tag_position)?,
+        };
+        Ok((file_header, encrypted_data))
+    }
+
+    // For the mode specific file functions, which cannot switch to another cipher
+    fn open_file_as<'a>(&self, data: &'a [u8], mode: CipherMode, hmac_key: &[u8]) -> Result<(FileHeader<'a>, Vec<u8>), CryptError> {
+        let (file_header, encrypted_data) = self.open_file(data, hmac_key)?;
+        if file_header.mode != mode {
+            return Err(CryptError::InvalidParameters);
+        }
+        Ok((file_header, encrypted_data))
+    }
+
+    // Decrypts according to the configured mode and tag length, the nonce is only used by XChaCha20
+    pub async fn decrypt_configured(&self, encrypted_data_with_hmac: &[u8], key: &dyn SharedSecret, hmac_key: &[u8], nonce: Option<&[u8; 24]>) -> Result<Vec<u8>, CryptError> {
+        self.decrypt_with_mode(self.mode, encrypted_data_with_hmac, None, key, hmac_key, nonce).await
+    }
+
+    // aad is the authenticated file header, if any
+    async fn decrypt_with_mode(&self, mode: CipherMode, encrypted_data_with_hmac: &[u8], aad: Option<&[u8]>, key: &dyn SharedSecret, hmac_key: &[u8], nonce: Option<&[u8; 24]>) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let encrypted_data = envelope::verify_truncated_hmac_at(hmac_key, encrypted_data_with_hmac, aad, self.hmac_len, self.tag_position)?;
+
+        match mode {
+            CipherMode::Aes => envelope::decrypt_data(&encrypted_data, key.as_bytes()),
+            #[cfg(feature = "xchacha20")]
+            CipherMode::XChaCha20 => {
+                let nonce = nonce.ok_or(CryptError::InvalidParameters)?;
+                self.decrypt_data_xchacha20(&encrypted_data, nonce, key.as_bytes()).await
+            },
+            #[cfg(not(feature = "xchacha20"))]
+            CipherMode::XChaCha20 => {
+                let _ = nonce;
+                Err(CryptError::NotImplemented("xchacha20 feature not enabled"))
+            },
+        }
+    }
+
+    // The cipher is taken from the file header rather than the configured mode
+    pub async fn decrypt_file_configured(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8], nonce: Option<&[u8; 24]>) -> Result<Vec<u8>, CryptError> {
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (file_header, data) = header::read_file_header(&data)?;
+        let nonce = match file_header.mode {
+            CipherMode::Aes => nonce,
+            CipherMode::XChaCha20 => Some(file_nonce(&file_header, nonce)?),
+        };
+        let decrypted_data = self.decrypt_with_mode(file_header.mode, data, file_header.authenticated, key, hmac_key, nonce).await?;
+        let decrypted_data = self.decompress(file_header.compressed, decrypted_data)?;
+
+        if self.write_output {
+            let encrypted_path = encrypted_file_path.to_str().ok_or(CryptError::PathError)?;
+            let decrypt_file_path = header::restore_extension(Self::original_file_path(encrypted_path), file_header.extension);
+            self.write_decrypted_file(&decrypt_file_path, &decrypted_data)?;
+        }
+        Ok(decrypted_data)
     }
     pub async fn generate_original_filename<'a>(&self, encrypted_path: &'a str) -> String {
-       // let encrypted_path = format!("./{}", encrypted_path);
+        Self::original_file_path(encrypted_path)
+    }
+
+    // Where decrypt_file would write, without touching the file. Only a name that lost its extension
+    // differs, since decryption appends the one recorded in the header
+    pub fn original_filename(encrypted_path: &str) -> PathBuf {
+        PathBuf::from(Self::original_file_path(encrypted_path))
+    }
+
+    fn original_file_path(encrypted_path: &str) -> String {
         let path = std::path::Path::new(&encrypted_path);
         let dir = path.parent().unwrap_or_else(|| std::path::Path::new(""));
         let mut file_name = path.file_stem().unwrap().to_str().unwrap().to_string();
@@ -69,117 +331,229 @@ impl Decrypt {
         format!("{}/{}", dir.display(), file_name)
     }
 
-    pub fn extract_signature(signed_data: &[u8]) -> Result<(Vec<u8>, falcon1024::DetachedSignature), CryptError> {
-        let mut cursor = Cursor::new(signed_data);
+    // The algorithm id recorded in signed data, so callers can pick the matching extract_signature function
+    pub fn signature_algorithm(signed_data: &[u8]) -> Result<SignatureAlgorithm, CryptError> {
+        framing::read_signed(signed_data).map(|(algorithm, _, _)| algorithm)
+    }
 
-        // Read the length of the data
-        let data_length = cursor.read_u64::<BigEndian>().unwrap() as usize;
+    // Reads signed data of any algorithm, legacy data without the version byte holds a Falcon-1024 signature
+    pub fn extract_embedded_signature(signed_data: &[u8]) -> Result<(Vec<u8>, EmbeddedSignature), CryptError> {
+        let (algorithm, data, signature) = framing::read_signed(signed_data)?;
+        let signature = match algorithm {
+            SignatureAlgorithm::Falcon1024 => {
+                check_falcon_signature_len(signature)?;
+                EmbeddedSignature::Falcon1024(Box::new(DetachedSignatureSign::from_bytes(signature).map_err(|_| CryptError::InvalidSignature)?))
+            },
+            #[cfg(feature = "dilithium")]
+            SignatureAlgorithm::Dilithium5 => {
+                if signature.is_empty() || signature.len() > dilithium5::signature_bytes() {
+                    return Err(CryptError::InvalidSignatureLength { expected: dilithium5::signature_bytes(), got: signature.len() });
+                }
+                EmbeddedSignature::Dilithium5(Box::new(DetachedSignatureSign::from_bytes(signature).map_err(|_| CryptError::InvalidSignature)?))
+            },
+            #[cfg(not(feature = "dilithium"))]
+            SignatureAlgorithm::Dilithium5 => return Err(CryptError::UnsupportedSignatureAlgorithm(algorithm.id())),
+        };
+        Ok((data.to_vec(), signature))
+    }
 
-        // Validate the length to avoid panics
-        if data_length > signed_data.len() {
-            return Err(CryptError::InvalidSignatureLength);
+    pub fn extract_signature(signed_data: &[u8]) -> Result<(Vec<u8>, falcon1024::DetachedSignature), CryptError> {
+        match Self::extract_embedded_signature(signed_data)? {
+            (data, EmbeddedSignature::Falcon1024(signature)) => Ok((data, *signature)),
+            #[cfg(feature = "dilithium")]
+            (_, EmbeddedSignature::Dilithium5(_)) => Err(CryptError::UnsupportedSignatureAlgorithm(SignatureAlgorithm::Dilithium5.id())),
         }
-
-        // Extract the data
-        let data = signed_data[8..(8 + data_length)].to_vec();
-        let signature = &signed_data[(8 + data_length)..];
-
-        // The remaining part is the signature
-        let signature: falcon1024::DetachedSignature = DetachedSignatureSign::from_bytes(&signature).unwrap();
-        Ok((data, signature))
     }
 
-    pub fn verify_signature(&self, signature: falcon1024::DetachedSignature, message: &[u8], public_key: &falcon1024::PublicKey) -> Result<bool, SigningErr> {
+    pub fn verify_signature(&self, signature: falcon1024::DetachedSignature, message: &[u8], public_key: &falcon1024::PublicKey) -> Result<bool, CryptError> {
         // Perform the signature verification
         match falcon1024::verify_detached_signature(&signature, message, public_key) {
             Ok(_) => Ok(true),
-            Err(_) => Err(SigningErr::SignatureVerificationFailed),
+            Err(_) => Err(CryptError::SignatureVerificationFailed),
         }
     }
 
+    // For signatures straight off the wire, a malformed length is reported instead of reaching from_bytes
+    pub fn verify_signature_bytes(&self, sig_bytes: &[u8], message: &[u8], public_key: &falcon1024::PublicKey) -> Result<bool, CryptError> {
+        check_falcon_signature_len(sig_bytes)?;
+        let signature: falcon1024::DetachedSignature = DetachedSignatureSign::from_bytes(sig_bytes)
+            .map_err(|_| CryptError::InvalidSignatureLength { expected: falcon1024::signature_bytes(), got: sig_bytes.len() })?;
+        self.verify_signature(signature, message, public_key)
+    }
 
+    // Counterpart of Encrypt::sign_file_digest, the file is hashed in chunks and never held in memory
+    pub fn verify_file_signature_streaming(&self, file_path: &Path, signature: &falcon1024::DetachedSignature, public_key: &falcon1024::PublicKey) -> Result<bool, CryptError> {
+        let digest = file_digest(file_path)?;
+        match falcon1024::verify_detached_signature(signature, &digest, public_key) {
+            Ok(_) => Ok(true),
+            Err(_) => Err(CryptError::SignatureVerificationFailed),
+        }
+    }
 
+    #[cfg(feature = "dilithium")]
+    pub fn extract_signature_dilithium(signed_data: &[u8]) -> Result<(Vec<u8>, dilithium5::DetachedSignature), CryptError> {
+        match Self::extract_embedded_signature(signed_data)? {
+            (data, EmbeddedSignature::Dilithium5(signature)) => Ok((data, *signature)),
+            (_, EmbeddedSignature::Falcon1024(_)) => Err(CryptError::UnsupportedSignatureAlgorithm(SignatureAlgorithm::Falcon1024.id())),
+        }
+    }
 
-    // Function to verify the HMAC of the data
-    pub fn verify_hmac(&self, key: &[u8], data_with_hmac: &[u8], hmac_len: usize) -> Result<Vec<u8>, &'static str> {
-        if data_with_hmac.len() < hmac_len {
-            return Err("Data is too short for HMAC verification");
+    #[cfg(feature = "dilithium")]
+    pub fn verify_signature_dilithium(&self, signature: dilithium5::DetachedSignature, message: &[u8], public_key: &dilithium5::PublicKey) -> Result<bool, CryptError> {
+        match dilithium5::verify_detached_signature(&signature, message, public_key) {
+            Ok(_) => Ok(true),
+            Err(_) => Err(CryptError::SignatureVerificationFailed),
         }
+    }
 
-        let (data, hmac) = data_with_hmac.split_at(data_with_hmac.len() - hmac_len);
-        let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(key)
-            .expect("HMAC can take key of any size");
 
-        mac.update(data);
 
-        if let Err(_) = mac.verify_slice(hmac) {
-            eprintln!("HMAC verification failed!");
-            //eprintln!("Data: {:?}", data);
-            eprintln!("HMAC: {:?}", hmac);
-            return Err("HMAC verification failed");
-        }
 
+    // Function to verify the HMAC of the data
+    pub fn verify_hmac(&self, key: &[u8], data_with_hmac: &[u8], hmac_len: usize) -> Result<Vec<u8>, &'static str> {
+        let (data, tag) = envelope::split_tag(data_with_hmac, hmac_len, self.tag_position)
+            .map_err(|_| "Data is too short for HMAC verification")?;
+        self.check_hmac(key, data, tag).map_err(|_| "HMAC verification failed")?;
         Ok(data.to_vec())
     }
 
+    // Verification only, nothing about the received tag is printed or returned. Only the full
+    // HMAC_LEN byte tag is accepted
+    pub fn check_hmac(&self, key: &[u8], data: &[u8], tag: &[u8]) -> Result<(), CryptError> {
+        if tag.len() != envelope::HMAC_LEN {
+            return Err(CryptError::HmacVerificationError);
+        }
+        envelope::check_hmac(key, data, tag)
+    }
+
 
     pub fn extract_encrypted_message(&self, message: &str) -> Result<Vec<u8>, CryptError> {
         let begin_tag = "-----BEGIN ENCRYPTED MESSAGE-----";
         let end_tag = "-----END ENCRYPTED MESSAGE-----";
 
-        if let (Some(start), Some(end)) = (message.find(begin_tag), message.find(end_tag)) {
-            if start < end {
-                let encrypted_message = &message[start + begin_tag.len()..end].trim();
-                Ok(hex::decode(encrypted_message).unwrap())
-            } else {
-                Err(CryptError::InvalidMessageFormat)
-            }
-        } else {
-            Err(CryptError::MissingData)
+        if !message.contains(end_tag) {
+            return Err(CryptError::MissingData);
         }
+        let start = message.find(begin_tag).ok_or(CryptError::MissingData)?;
+
+        // Only an end tag after the begin tag closes the message
+        let content_start = start + begin_tag.len();
+        let end = message[content_start..].find(end_tag)
+            .map(|end| content_start + end)
+            .ok_or(CryptError::InvalidMessageFormat)?;
+
+        let encrypted_message = message[content_start..end].trim();
+        hex::decode(encrypted_message).map_err(|err| CryptError::HexDecodingError(err.to_string()))
     }
 
+    // The AES and HMAC keys are both derived from the decapsulated shared secret with DerivedKeys. For
+    // MessageAction decrypt is either the armored message Encrypt::encrypt saves to ./message.enc or its bare
+    // hex contents, anything else fails with HexDecodingError
     pub async fn decrypt(
         &self, 
         secret_key: PathBuf,
         ciphertext: PathBuf,
         decrypt: &str,
         action: ActionType,
+        nonce: Option<&[u8; 24]>,
+    ) -> Result<Vec<u8>, CryptError> {
+        let (secret, cipher) = Self::load_decryption_keys(secret_key, ciphertext).await?;
+        self.decrypt_with_keys(&secret, &cipher, decrypt, action, nonce).await
+    }
+
+    // Same as decrypt, with the shared secret used as the AES key and the HMAC key supplied by the caller
+    pub async fn decrypt_with_hmac_key(
+        &self,
+        secret_key: PathBuf,
+        ciphertext: PathBuf,
+        decrypt: &str,
+        action: ActionType,
         hmac_key: &[u8],
         nonce: Option<&[u8; 24]>,
-    ) -> Result<(), CryptError> {
-        let mut keychain = Keychain::new().unwrap();
+    ) -> Result<Vec<u8>, CryptError> {
+        let (secret, cipher) = Self::load_decryption_keys(secret_key, ciphertext).await?;
+        self.decrypt_with_keys_and_hmac_key(&secret, &cipher, decrypt, action, hmac_key, nonce).await
+    }
+
+    async fn load_decryption_keys(secret_key: PathBuf, ciphertext: PathBuf) -> Result<(mceliece8192128::SecretKey, mceliece8192128::Ciphertext), CryptError> {
+        let mut keychain = Keychain::default();
 
         // Load the secret key and ciphertext
         let secret = keychain.load_secret_key(secret_key).await?;
         let cipher = keychain.load_ciphertext(ciphertext).await?;
+        Ok((secret, cipher))
+    }
+
+    // Same as decrypt for keys that are already in memory
+    pub async fn decrypt_with_keys(
+        &self,
+        secret_key: &mceliece8192128::SecretKey,
+        ciphertext: &mceliece8192128::Ciphertext,
+        decrypt: &str,
+        action: ActionType,
+        nonce: Option<&[u8; 24]>,
+    ) -> Result<Vec<u8>, CryptError> {
+        let keys = DerivedKeys::from_shared_secret(&decapsulate(ciphertext, secret_key));
+        self.decrypt_action(&keys.aes_key_as_shared_secret(), decrypt, action, &keys.hmac_key, nonce).await
+    }
 
+    // Same as decrypt_with_hmac_key for keys that are already in memory
+    pub async fn decrypt_with_keys_and_hmac_key(
+        &self,
+        secret_key: &mceliece8192128::SecretKey,
+        ciphertext: &mceliece8192128::Ciphertext,
+        decrypt: &str,
+        action: ActionType,
+        hmac_key: &[u8],
+        nonce: Option<&[u8; 24]>,
+    ) -> Result<Vec<u8>, CryptError> {
         // Decapsulate using the secret key
-        let shared_secret = decapsulate(&cipher, &secret);
+        let shared_secret = decapsulate(ciphertext, secret_key);
+        self.decrypt_action(&shared_secret, decrypt, action, hmac_key, nonce).await
+    }
 
+    async fn decrypt_action(
+        &self,
+        shared_secret: &dyn SharedSecret,
+        decrypt: &str,
+        action: ActionType,
+        hmac_key: &[u8],
+        nonce: Option<&[u8; 24]>,
+    ) -> Result<Vec<u8>, CryptError> {
         match action {
             ActionType::FileAction => {
                 let path = PathBuf::from(decrypt);
-                println!("Decrypting file...");
-
-                #[cfg(feature = "default")]
-                let _ = self.decrypt_file(&path, &shared_secret, hmac_key).await?;
-                #[cfg(feature = "xchacha20")]
-                let _ = self.decrypt_file_xchacha20(&path, &shared_secret, nonce.unwrap(), hmac_key).await?;
+                tracing::debug!("decrypting file {}", decrypt);
 
-                Ok(())
+                // The cipher comes from the file header, so one build handles both formats
+                self.decrypt_file_configured(&path, shared_secret, hmac_key, nonce).await
             },
             ActionType::MessageAction => {
-                println!("Decrypting message...\n");
+                tracing::debug!("decrypting message");
 
-                #[cfg(feature = "default")]
-                let _ = self.decrypt_msg(decrypt.as_bytes(), &shared_secret, hmac_key, true).await?;
-                #[cfg(feature = "xchacha20")]
-                let _ = self.decrypt_msg_xchacha20(decrypt.as_bytes(), &shared_secret, nonce.unwrap(), hmac_key, true).await?;
+                // Encrypt::encrypt saves the message as armored hex, which is accepted as is or without the armor
+                let message = if decrypt.contains("-----BEGIN ENCRYPTED MESSAGE-----") {
+                    self.extract_encrypted_message(decrypt)?
+                } else {
+                    hex::decode(decrypt).map_err(|err| CryptError::HexDecodingError(err.to_string()))?
+                };
+                // message_path is only written when write_output is set, the plaintext is returned either way
+                let plaintext = match self.mode {
+                    #[cfg(feature = "default")]
+                    CipherMode::Aes => self.decrypt_msg(&message, shared_secret, hmac_key, self.write_output).await?,
+                    #[cfg(feature = "xchacha20")]
+                    CipherMode::XChaCha20 => {
+                        let nonce = nonce.ok_or(CryptError::InvalidParameters)?;
+                        self.decrypt_msg_xchacha20(&message, shared_secret, nonce, hmac_key, self.write_output).await?
+                    },
+                    #[cfg(not(feature = "default"))]
+                    CipherMode::Aes => return Err(CryptError::NotImplemented("default feature with the AES implementation not enabled")),
+                    #[cfg(not(feature = "xchacha20"))]
+                    CipherMode::XChaCha20 => return Err(CryptError::NotImplemented("xchacha20 feature not enabled")),
+                };
 
-                Ok(())
+                Ok(plaintext.into_bytes())
             },
-            _ => Err(CryptError::InvalidParameters),
         }
     }
 }
@@ -188,49 +562,494 @@ impl Decrypt {
 #[cfg(feature = "default")]
 impl Decrypt {
     pub async fn decrypt_data(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptError> {
-        let mut decrypted_data = vec![0u8; data.len()];
-        let cipher = Aes256::new(GenericArray::from_slice(key));
-        for (chunk, decrypted_chunk) in data.chunks(16).zip(decrypted_data.chunks_mut(16)) {
-            let mut block = GenericArray::clone_from_slice(chunk); // Create a mutable copy
-            cipher.decrypt_block(&mut block);
-            decrypted_chunk.copy_from_slice(&block);
+        envelope::decrypt_data(data, key)
+    }
+
+    // Allocation free counterpart of decrypt_data for decrypting many messages into one buffer
+    pub async fn decrypt_data_into(&self, data: &[u8], key: &[u8], out: &mut Vec<u8>) -> Result<(), CryptError> {
+        envelope::decrypt_data_into(data, key, out)
+    }
+
+    pub async fn decrypt_data_with_aad(&self, encrypted_data_with_hmac: &[u8], key: &[u8], hmac_key: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key, hmac_key)?;
+        let encrypted_data = envelope::verify_hmac_with_aad(hmac_key, encrypted_data_with_hmac, aad, envelope::HMAC_LEN)
+            .map_err(|_| CryptError::HmacVerificationError)?;
+        envelope::decrypt_data(&encrypted_data, key)
+    }
+
+    pub async fn decrypt_with_key(&self, data: &[u8], aes_key: &[u8; 32], hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(aes_key, hmac_key)?;
+        let encrypted_data = self.verify_hmac(hmac_key, data, 64)
+            .map_err(|_| CryptError::HmacVerificationError)?;
+        envelope::decrypt_data(&encrypted_data, aes_key)
+    }
+
+    // The plaintext buffer is wiped when dropped, for decrypted secrets that are consumed right away
+    pub async fn decrypt_bytes_zeroizing(&self, encrypted_data_with_hmac: &[u8], key: &dyn SharedSecret, hmac_key: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let encrypted_data = self.verify_hmac(hmac_key, encrypted_data_with_hmac, 64)
+            .map_err(|_| CryptError::HmacVerificationError)?;
+        Ok(Zeroizing::new(envelope::decrypt_data(&encrypted_data, key.as_bytes())?))
+    }
+
+    pub async fn decrypt_data_derived(&self, data: &[u8], shared_secret: &dyn SharedSecret) -> Result<Vec<u8>, CryptError> {
+        let keys = DerivedKeys::from_shared_secret(shared_secret);
+        self.decrypt_with_key(data, &keys.aes_key, &keys.hmac_key).await
+    }
+
+    // Counterpart of Encrypt::encrypt_data_runtime, the shared secret is decapsulated from the keychain's ciphertext
+    pub async fn decrypt_data_runtime(&self, data: &[u8], keychain: &RuntimeKeychain) -> Result<Vec<u8>, CryptError> {
+        let keys = DerivedKeys::from_secret_bytes(&keychain.decapsulate()?);
+        self.decrypt_with_key(data, &keys.aes_key, &keys.hmac_key).await
+    }
+
+    // The cipher is taken from the header, XChaCha20 files are decrypted with the nonce their header records
+    pub async fn decrypt_file(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8]) -> Result<PathBuf, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let decrypted_file_path = encrypted_file_path.as_os_str().to_str().ok_or(CryptError::PathError)?;
+
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (file_header, encrypted_data) = self.open_file(&data, hmac_key)?;
+        let decrypt_file_path = header::restore_extension(self.generate_original_filename(decrypted_file_path).await, file_header.extension);
+        tracing::debug!("decrypted file path: {:?}", decrypt_file_path);
+        let decrypted_data = match file_header.mode {
+            CipherMode::Aes => self.decrypt_data(&encrypted_data, key.as_bytes()).await?,
+            #[cfg(feature = "xchacha20")]
+            CipherMode::XChaCha20 => self.decrypt_data_xchacha20(&encrypted_data, file_nonce(&file_header, None)?, key.as_bytes()).await?,
+            #[cfg(not(feature = "xchacha20"))]
+            CipherMode::XChaCha20 => return Err(CryptError::NotImplemented("xchacha20 feature not enabled")),
+        };
+        let decrypted_data = self.decompress(file_header.compressed, decrypted_data)?;
+
+        self.write_decrypted_file(&decrypt_file_path, &decrypted_data)?;
+
+        tracing::info!("decryption completed and file written to {:?}", decrypt_file_path);
+        Ok(PathBuf::from(decrypt_file_path))
+    }
+
+    // Reports (bytes_processed, total_bytes) of the encrypted file, ending with processed == total
+    pub async fn decrypt_file_with_progress(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8], mut progress: impl FnMut(u64, u64)) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let decrypted_file_path = encrypted_file_path.as_os_str().to_str().ok_or(CryptError::PathError)?;
+
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let total_bytes = data.len() as u64;
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+        let decrypt_file_path = header::restore_extension(self.generate_original_filename(decrypted_file_path).await, file_header.extension);
+
+        let cipher = Aes256::new(GenericArray::from_slice(key.as_bytes()));
+        let mut decrypted_data = vec![0u8; encrypted_data.len()];
+        let mut processed = 0u64;
+        for (chunk, decrypted_chunk) in encrypted_data.chunks(PROGRESS_CHUNK_SIZE).zip(decrypted_data.chunks_mut(PROGRESS_CHUNK_SIZE)) {
+            envelope::decrypt_blocks_into(&cipher, chunk, decrypted_chunk);
+            processed += chunk.len() as u64;
+            progress(processed, total_bytes);
         }
 
         // Remove padding if present
         while decrypted_data.last() == Some(&0) {
             decrypted_data.pop();
         }
+        let decrypted_data = self.decompress(file_header.compressed, decrypted_data)?;
 
+        self.write_decrypted_file(&decrypt_file_path, &decrypted_data)?;
+        // The trailing HMAC tag accounts for the remaining bytes
+        progress(total_bytes, total_bytes);
         Ok(decrypted_data)
     }
 
-    pub async fn decrypt_file(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+    // Writes the plaintext chunk by chunk, checking the cancel flag in between, a cancelled run removes the partial output
+    pub async fn decrypt_file_streaming(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8], cancel: &AtomicBool, mut progress: impl FnMut(u64, u64)) -> Result<PathBuf, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
         let decrypted_file_path = encrypted_file_path.as_os_str().to_str().ok_or(CryptError::PathError)?;
-        let decrypt_file_path = self.generate_original_filename(decrypted_file_path).await;
-        println!("Decrypted file path: {:?}", decrypt_file_path);
 
-        let data = fs::read(&encrypted_file_path).map_err(|_| CryptError::IOError)?;
-        let encrypted_data = self.verify_hmac(hmac_key, &data, 64).unwrap();
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let total_bytes = data.len() as u64;
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+        // Chunks are written as they are decrypted, which gzip data does not allow
+        if file_header.compressed {
+            return Err(CryptError::NotImplemented("streaming decryption of compressed files"));
+        }
+        let decrypt_file_path = PathBuf::from(header::restore_extension(self.generate_original_filename(decrypted_file_path).await, file_header.extension));
+
+        let mut output = self.create_output_file(&decrypt_file_path)?;
+        let cipher = Aes256::new(GenericArray::from_slice(key.as_bytes()));
+        let decrypt_chunk = |chunk: &[u8], decrypted_chunk: &mut [u8]| envelope::decrypt_blocks_into(&cipher, chunk, decrypted_chunk);
+        let result = Self::stream_decrypted_chunks(&mut output, &encrypted_data, decrypt_chunk, cancel, |processed| progress(processed, total_bytes));
+        drop(output);
+        if let Err(err) = result {
+            let _ = fs::remove_file(&decrypt_file_path);
+            return Err(err);
+        }
+
+        progress(total_bytes, total_bytes);
+        tracing::info!("decryption completed and file written to {:?}", decrypt_file_path);
+        Ok(decrypt_file_path)
+    }
+
+    // decrypt_chunk is handed the chunks in order and fills the output slice of the same length
+    fn stream_decrypted_chunks(output: &mut impl Write, encrypted_data: &[u8], mut decrypt_chunk: impl FnMut(&[u8], &mut [u8]), cancel: &AtomicBool, mut progress: impl FnMut(u64)) -> Result<(), CryptError> {
+        let mut decrypted_chunk = Zeroizing::new(vec![0u8; PROGRESS_CHUNK_SIZE]);
+        // Zeros are held back until a later non-zero byte shows they are not padding
+        let mut pending_zeros = 0usize;
+        let mut processed = 0u64;
+        for chunk in encrypted_data.chunks(PROGRESS_CHUNK_SIZE) {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(CryptError::Cancelled);
+            }
+            let decrypted_chunk = &mut decrypted_chunk[..chunk.len()];
+            decrypt_chunk(chunk, decrypted_chunk);
+
+            match decrypted_chunk.iter().rposition(|&byte| byte != 0) {
+                Some(last) => {
+                    io::copy(&mut io::repeat(0).take(pending_zeros as u64), output).map_err(|_| CryptError::WriteError)?;
+                    output.write_all(&decrypted_chunk[..=last]).map_err(|_| CryptError::WriteError)?;
+                    pending_zeros = decrypted_chunk.len() - last - 1;
+                }
+                None => pending_zeros += decrypted_chunk.len(),
+            }
+            processed += chunk.len() as u64;
+            progress(processed);
+        }
+        Ok(())
+    }
+
+    // Counterpart of Encrypt::seal_signed_file: decrypts, then verifies the embedded Falcon signature
+    pub async fn decrypt_and_verify(&self, encrypted_file_path: &PathBuf, secret_key: &mceliece8192128::SecretKey, ciphertext: &mceliece8192128::Ciphertext, falcon_public_key: &falcon1024::PublicKey, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        let shared_secret = decapsulate(ciphertext, secret_key);
+        self.open_signed_file(encrypted_file_path, &shared_secret, falcon_public_key, hmac_key).await
+    }
+
+    // decrypt_and_verify with the keys taken from a keychain whose usage allows signing, a stored shared
+    // secret is used as it is and otherwise decapsulated
+    pub async fn decrypt_and_verify_with_keychain(&self, encrypted_file_path: &PathBuf, keychain: &Keychain, falcon_public_key: &falcon1024::PublicKey, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        keychain.check_usage(KeyUsage::Both)?;
+        let shared_secret = match (keychain.shared_secret.as_ref(), keychain.ciphertext.as_ref(), keychain.secret_key.as_ref()) {
+            (Some(shared_secret), _, _) => *shared_secret,
+            (None, Some(ciphertext), Some(secret_key)) => decapsulate(ciphertext, secret_key),
+            _ => return Err(CryptError::MissingSharedSecret),
+        };
+        self.open_signed_file(encrypted_file_path, &shared_secret, falcon_public_key, hmac_key).await
+    }
+
+    async fn open_signed_file(&self, encrypted_file_path: &PathBuf, shared_secret: &mceliece8192128::SharedSecret, falcon_public_key: &falcon1024::PublicKey, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(shared_secret.as_bytes(), hmac_key)?;
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (_, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+        let signed_data = self.decrypt_data(&encrypted_data, shared_secret.as_bytes()).await?;
+
+        let (message, signature) = Self::extract_signature(&signed_data)?;
+        self.verify_signature(signature, &message, falcon_public_key)?;
+        Ok(message)
+    }
+
+    // Dry run of decrypt_file: checks the HMAC and, given a falcon key, the embedded signature without writing output
+    pub async fn verify_file(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8], falcon_public_key: Option<&falcon1024::PublicKey>) -> Result<(), CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (_, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+
+        if let Some(falcon_public_key) = falcon_public_key {
+            let signed_data = self.decrypt_data(&encrypted_data, key.as_bytes()).await?;
+            let (message, signature) = Self::extract_signature(&signed_data)?;
+            self.verify_signature(signature, &message, falcon_public_key)?;
+        }
+        Ok(())
+    }
+
+    // Pipeline variant of decrypt_file, the whole input is buffered so the HMAC is verified before anything is written
+    pub async fn decrypt_reader_to_writer(&self, mut reader: impl Read, mut writer: impl Write, key: &dyn SharedSecret, hmac_key: &[u8]) -> Result<(), CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+        let decrypted_data = self.decompress(file_header.compressed, self.decrypt_data(&encrypted_data, key.as_bytes()).await?)?;
+
+        writer.write_all(&decrypted_data)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Counterpart of Encrypt::encrypt_directory, links and entries escaping output_dir are rejected before anything is unpacked
+    #[cfg(feature = "tar")]
+    pub async fn decrypt_archive(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8], output_dir: &Path) -> Result<(), CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (_, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
         let decrypted_data = self.decrypt_data(&encrypted_data, key.as_bytes()).await?;
+        let archive = match decrypted_data.split_last() {
+            Some((&ARCHIVE_END_MARKER, archive)) => archive,
+            _ => return Err(CryptError::InvalidMessageFormat),
+        };
 
-        fs::write(&decrypt_file_path, &decrypted_data).map_err(|_| CryptError::WriteError)?;
+        // Links could point anywhere, so only plain files and directories are unpacked
+        let mut entries = tar::Archive::new(archive);
+        for entry in entries.entries()? {
+            let entry = entry?;
+            if !matches!(entry.header().entry_type(), tar::EntryType::Regular | tar::EntryType::Directory) {
+                return Err(CryptError::InvalidMessageFormat);
+            }
+            let path = entry.path()?;
+            if !path.components().all(|component| matches!(component, std::path::Component::Normal(_) | std::path::Component::CurDir)) {
+                return Err(CryptError::InvalidMessageFormat);
+            }
+        }
 
-        println!("Decryption completed and file written to {:?}", decrypt_file_path);
-        Ok(decrypted_data)
+        fs::create_dir_all(output_dir)?;
+        tar::Archive::new(archive).unpack(output_dir)?;
+        Ok(())
+    }
+
+    // Counterpart of Encrypt::encrypt_bundle, a wrong secret key surfaces as an HMAC failure
+    pub async fn decrypt_bundle(&self, bundle: &[u8], secret_key: &mceliece8192128::SecretKey) -> Result<Vec<u8>, CryptError> {
+        if bundle.len() < CIPHERTEXT_LEN + 2 + envelope::HMAC_LEN {
+            return Err(CryptError::InvalidMessageFormat);
+        }
+
+        let (authenticated, tag) = bundle.split_at(bundle.len() - envelope::HMAC_LEN);
+        let (ciphertext, rest) = authenticated.split_at(CIPHERTEXT_LEN);
+        let shared_secret = decapsulate(&ciphertext_from_slice(ciphertext)?, secret_key);
+        let keys = DerivedKeys::from_shared_secret(&shared_secret);
+        envelope::check_hmac(&keys.hmac_key, authenticated, tag)?;
+
+        let mode = CipherMode::from_id(rest[0])?;
+        let nonce_len = rest[1] as usize;
+        let payload = rest.get(2 + nonce_len..).ok_or(CryptError::InvalidMessageFormat)?;
+        match mode {
+            CipherMode::Aes => envelope::decrypt_data(payload, &keys.aes_key),
+            CipherMode::XChaCha20 => Err(CryptError::NotImplemented("XChaCha20 bundles")),
+        }
+    }
+
+    // Counterpart of Encrypt::encrypt_file_multi for the recipient owning wrapped_key
+    pub async fn decrypt_file_multi(&self, encrypted_file_path: &PathBuf, wrapped_key: &[u8], secret_key: &mceliece8192128::SecretKey, hmac_key: &[u8]) -> Result<PathBuf, CryptError> {
+        let content_key = unwrap_content_key(secret_key, wrapped_key)?;
+        let encrypted_path = encrypted_file_path.to_str().ok_or(CryptError::PathError)?;
+
+        check_distinct_keys(content_key.as_ref(), hmac_key)?;
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+        let decrypted_data = self.decompress(file_header.compressed, envelope::decrypt_data(&encrypted_data, content_key.as_ref())?)?;
+
+        let decrypt_file_path = header::restore_extension(Self::original_file_path(encrypted_path), file_header.extension);
+        self.write_decrypted_file(&decrypt_file_path, &decrypted_data)?;
+        Ok(PathBuf::from(decrypt_file_path))
+    }
+
+    // Forwards an encrypted file to a new recipient, the plaintext only ever exists in memory
+    pub async fn reencrypt_file(&self, encrypted_file_path: &PathBuf, old_secret_key: &mceliece8192128::SecretKey, old_ciphertext: &mceliece8192128::Ciphertext, new_public_key: &mceliece8192128::PublicKey, hmac_key: &[u8]) -> Result<(PathBuf, mceliece8192128::Ciphertext), CryptError> {
+        let old_shared_secret = decapsulate(old_ciphertext, old_secret_key);
+        check_distinct_keys(old_shared_secret.as_bytes(), hmac_key)?;
+
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+        let decrypted_data = self.decrypt_data(&encrypted_data, old_shared_secret.as_bytes()).await?;
+
+        let (new_shared_secret, new_ciphertext) = generate_encapsulation(new_public_key);
+        // The payload is passed on still compressed, so the flag carries over
+        let reencrypted_data = Encrypt::new().encrypt_file_data(&decrypted_data, new_shared_secret.as_bytes(), hmac_key, file_header.extension, file_header.compressed).await?;
+
+        let encrypted_path = encrypted_file_path.to_str().ok_or(CryptError::PathError)?;
+        let reencrypted_file_path = Keychain::generate_unique_filename(&Self::original_file_path(encrypted_path), "enc")?;
+        fs::write(&reencrypted_file_path, &reencrypted_data)?;
+        Ok((PathBuf::from(reencrypted_file_path), new_ciphertext))
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn decrypt_files(&self, paths: Vec<PathBuf>, shared_secret: &dyn SharedSecret, hmac_key: &[u8]) -> Vec<Result<PathBuf, CryptError>> {
+        let key = shared_secret.as_bytes().to_vec();
+        paths.par_iter()
+            .map(|path| self.decrypt_file_blocking(path, &key, hmac_key))
+            .collect()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn decrypt_file_blocking(&self, encrypted_file_path: &Path, key: &[u8], hmac_key: &[u8]) -> Result<PathBuf, CryptError> {
+        check_distinct_keys(key, hmac_key)?;
+        let decrypted_file_path = encrypted_file_path.as_os_str().to_str().ok_or(CryptError::PathError)?;
+
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::Aes, hmac_key)?;
+        let decrypt_file_path = PathBuf::from(header::restore_extension(Self::original_file_path(decrypted_file_path), file_header.extension));
+        let decrypted_data = self.decompress(file_header.compressed, envelope::decrypt_data(&encrypted_data, key)?)?;
+
+        self.write_decrypted_file(&decrypt_file_path, &decrypted_data)?;
+        Ok(decrypt_file_path)
+    }
+
+    // Same as decrypt_file, but the mapped file is decrypted chunk by chunk into a temporary file next to the output
+    // while the HMAC is computed over the same copied chunks, so neither is held in memory. The temporary file is
+    // only renamed to the output once the tag matches. Compressed files take the buffered path
+    #[cfg(feature = "mmap")]
+    pub async fn decrypt_file_mmap(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, hmac_key: &[u8]) -> Result<PathBuf, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let decrypted_file_path = encrypted_file_path.as_os_str().to_str().ok_or(CryptError::PathError)?;
+
+        let file = File::open(encrypted_file_path)?;
+        self.check_file_size(file.metadata()?.len())?;
+        // SAFETY: this assumes no other process truncates the file while it is mapped, which would fault on access.
+        // Other writes to it are tolerated: every chunk is read from the mapping exactly once into a local buffer,
+        // and that copy is both authenticated and decrypted, so bytes changed behind our back only make the tag
+        // check fail and never reach the output
+        let mapped = unsafe { Mmap::map(&file) }?;
+        // The header is parsed from a copy for the same reason, so the nonce and extension used are the ones authenticated
+        let header_copy = mapped[..mapped.len().min(header::MAX_FILE_HEADER_LEN)].to_vec();
+        let (file_header, rest) = header::read_file_header(&header_copy)?;
+        if file_header.compressed {
+            return self.decrypt_file(encrypted_file_path, key, hmac_key).await;
+        }
+
+        let data_with_hmac = &mapped[header_copy.len() - rest.len()..];
+        let (encrypted_data, tag) = envelope::split_tag(data_with_hmac, envelope::HMAC_LEN, self.tag_position)?;
+        let tag = tag.to_vec();
+        let mut verifier = match file_header.authenticated {
+            Some(header) => envelope::HmacVerifier::with_aad(hmac_key, header)?,
+            None => envelope::HmacVerifier::new(hmac_key)?,
+        };
+
+        let decrypt_file_path = PathBuf::from(header::restore_extension(self.generate_original_filename(decrypted_file_path).await, file_header.extension));
+        tracing::debug!("decrypted file path: {:?}", decrypt_file_path);
+        // Without overwrite the output is reserved up front, so an existing file is reported before any work is done
+        let reserved = !self.overwrite;
+        if reserved {
+            self.create_output_file(&decrypt_file_path)?;
+        }
+        let temp_path = PathBuf::from(temp_path_for(decrypt_file_path.to_str().ok_or(CryptError::PathError)?));
+        let temp_file = match fs::OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+            Ok(temp_file) => temp_file,
+            Err(_) => {
+                if reserved {
+                    let _ = fs::remove_file(&decrypt_file_path);
+                }
+                return Err(CryptError::WriteError);
+            },
+        };
+
+        let mut writer = BufWriter::new(temp_file);
+        let never_cancelled = AtomicBool::new(false);
+        let result = match file_header.mode {
+            CipherMode::Aes => {
+                let cipher = Aes256::new(GenericArray::from_slice(key.as_bytes()));
+                let decrypt_chunk = |chunk: &[u8], decrypted_chunk: &mut [u8]| {
+                    decrypted_chunk.copy_from_slice(chunk);
+                    verifier.update(decrypted_chunk);
+                    envelope::decrypt_blocks_in_place(&cipher, decrypted_chunk);
+                };
+                Self::stream_decrypted_chunks(&mut writer, encrypted_data, decrypt_chunk, &never_cancelled, |_| {})
+            },
+            #[cfg(feature = "xchacha20")]
+            CipherMode::XChaCha20 => {
+                let nonce = file_nonce(&file_header, None)?;
+                let mut cipher = XChaCha20::new(GenericArray::from_slice(key.as_bytes()), GenericArray::from_slice(nonce));
+                let decrypt_chunk = |chunk: &[u8], decrypted_chunk: &mut [u8]| {
+                    decrypted_chunk.copy_from_slice(chunk);
+                    verifier.update(decrypted_chunk);
+                    cipher.apply_keystream(decrypted_chunk);
+                };
+                Self::stream_decrypted_chunks(&mut writer, encrypted_data, decrypt_chunk, &never_cancelled, |_| {})
+            },
+            #[cfg(not(feature = "xchacha20"))]
+            CipherMode::XChaCha20 => Err(CryptError::NotImplemented("xchacha20 feature not enabled")),
+        };
+        let result = result
+            .and_then(|()| writer.flush().map_err(|_| CryptError::WriteError))
+            .and_then(|()| writer.get_ref().sync_all().map_err(|_| CryptError::WriteError));
+        drop(writer);
+        let result = result
+            .and_then(|()| verifier.finalize(&tag))
+            .and_then(|()| fs::rename(&temp_path, &decrypt_file_path).map_err(|_| CryptError::WriteError));
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp_path);
+            if reserved {
+                let _ = fs::remove_file(&decrypt_file_path);
+            }
+            return Err(err);
+        }
+
+        tracing::info!("decryption completed and file written to {:?}", decrypt_file_path);
+        Ok(decrypt_file_path)
     }
 
     pub async fn decrypt_msg(&self, encrypted_data_with_hmac: &[u8], key: &dyn SharedSecret, hmac_key: &[u8], safe: bool) -> Result<String, CryptError> {
-        let encrypted_data = self.verify_hmac(hmac_key, encrypted_data_with_hmac, 64).unwrap();
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let encrypted_data = envelope::verify_hmac_at(hmac_key, encrypted_data_with_hmac, 64, self.tag_position)?;
         let decrypted_data = self.decrypt_data(&encrypted_data, key.as_bytes()).await?;
         let decrypted_str = String::from_utf8(decrypted_data)
             .map_err(|_| CryptError::Utf8Error)?;
         if safe {
-            let message_file = fs::File::create("./message.txt");
-            write!(message_file.unwrap(), "{}", &decrypted_str).unwrap();
+            let mut message_file = fs::File::create(&self.message_path)?;
+            write!(message_file, "{}", &decrypted_str)?;
         }
-        println!("{}", &decrypted_str);
+        tracing::debug!("decrypted a message of {} bytes", decrypted_str.len());
         Ok(decrypted_str)
     }
+
+    // Every check (length, HMAC, block alignment, UTF-8) is evaluated before any of them is acted
+    // upon, and the HMAC comparison and padding strip don't branch on the data. UTF-8 validation is
+    // not constant time, its duration depends on the decrypted bytes whether or not the HMAC matched.
+    pub async fn decrypt_msg_constant_time(&self, encrypted_data_with_hmac: &[u8], key: &dyn SharedSecret, hmac_key: &[u8]) -> Result<String, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let hmac_len = 64;
+        let long_enough = Choice::from((encrypted_data_with_hmac.len() >= hmac_len) as u8);
+        let (data, hmac) = encrypted_data_with_hmac.split_at(encrypted_data_with_hmac.len().saturating_sub(hmac_len));
+
+        let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(hmac_key)
+            .expect("HMAC can take key of any size");
+        mac.update(data);
+        let expected_hmac = mac.finalize().into_bytes();
+        let mut received_hmac = [0u8; 64];
+        received_hmac[..hmac.len()].copy_from_slice(hmac);
+        let hmac_valid = expected_hmac.as_slice().ct_eq(&received_hmac);
+
+        let aligned_len = data.len() - data.len() % 16;
+        let aligned = Choice::from((aligned_len == data.len()) as u8);
+        let mut decrypted_data = envelope::decrypt_blocks(&data[..aligned_len], key.as_bytes())?;
+        decrypted_data.truncate(unpadded_len(&decrypted_data));
+        let utf8_valid = Choice::from(str::from_utf8(&decrypted_data).is_ok() as u8);
+
+        if bool::from(long_enough & hmac_valid & aligned & utf8_valid) {
+            String::from_utf8(decrypted_data).map_err(|_| CryptError::DecryptionFailed)
+        } else {
+            Err(CryptError::DecryptionFailed)
+        }
+    }
+}
+
+// Length without the trailing zero padding, every byte is visited and none of them is branched on
+fn unpadded_len(data: &[u8]) -> usize {
+    let mut len = 0u64;
+    for (index, byte) in data.iter().enumerate() {
+        len = u64::conditional_select(&(index as u64 + 1), &len, byte.ct_eq(&0));
+    }
+    len as usize
+}
+
+#[cfg(feature = "ctr")]
+impl Decrypt {
+    // Takes the nonce prefixed ciphertext produced by encrypt_data_ctr once its HMAC has been verified
+    pub async fn decrypt_data_ctr(&self, encrypted_data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        let len = encrypted_data.len().saturating_sub(CTR_NONCE_LEN);
+        self.decrypt_slice_ctr(encrypted_data, key, 0, len).await
+    }
+
+    // Decrypts len bytes starting at the plaintext offset by seeking the keystream, the rest is left untouched
+    pub async fn decrypt_slice_ctr(&self, encrypted_data: &[u8], key: &[u8], offset: u64, len: usize) -> Result<Vec<u8>, CryptError> {
+        if encrypted_data.len() < CTR_NONCE_LEN {
+            return Err(CryptError::InvalidMessageFormat);
+        }
+        let (nonce, ciphertext) = encrypted_data.split_at(CTR_NONCE_LEN);
+        let start = usize::try_from(offset).ok()
+            .filter(|start| start.checked_add(len).is_some_and(|end| end <= ciphertext.len()))
+            .ok_or(CryptError::InvalidParameters)?;
+
+        let mut cipher = Aes256Ctr::new_from_slices(key, nonce)
+            .map_err(|_| CryptError::InvalidKeyLength { expected: 32, actual: key.len() })?;
+        cipher.seek(offset);
+        let mut decrypted_data = ciphertext[start..start + len].to_vec();
+        cipher.apply_keystream(&mut decrypted_data);
+        Ok(decrypted_data)
+    }
 }
 
 #[cfg(feature = "xchacha20")]
@@ -249,33 +1068,71 @@ impl Decrypt {
     }
 
     pub async fn decrypt_file_xchacha20(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, nonce: &[u8; 24], hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
         let decrypted_file_path = encrypted_file_path.as_os_str().to_str().ok_or(CryptError::PathError)?;
-        let decrypt_file_path = self.generate_original_filename(decrypted_file_path).await;
-        println!("Decrypted file path: {:?}", decrypt_file_path);
-
-        let data = fs::read(&encrypted_file_path).map_err(|_| CryptError::IOError)?;
 
-        let encrypted_data = self.verify_hmac(hmac_key, data.as_slice(), 64).unwrap();
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::XChaCha20, hmac_key)?;
+        let nonce = file_nonce(&file_header, Some(nonce))?;
+        let decrypt_file_path = header::restore_extension(self.generate_original_filename(decrypted_file_path).await, file_header.extension);
+        tracing::debug!("decrypted file path: {:?}", decrypt_file_path);
 
         // Decrypt the data
-        let decrypted_data = self.decrypt_data_xchacha20(&encrypted_data, &nonce, key.as_bytes()).await?;
+        let decrypted_data = self.decompress(file_header.compressed, self.decrypt_data_xchacha20(&encrypted_data, &nonce, key.as_bytes()).await?)?;
+
+        self.write_decrypted_file(&decrypt_file_path, &decrypted_data)?;
+
+        tracing::info!("decryption completed and file written to {:?}", decrypt_file_path);
+        Ok(decrypted_data)
+    }
+
+    // Continues a decryption whose output already holds the first byte_offset bytes of plaintext. The HMAC
+    // still covers the whole file and is checked before the keystream is seeked to the offset
+    pub async fn decrypt_file_xchacha20_resume(&self, encrypted_file_path: &PathBuf, key: &dyn SharedSecret, nonce: &[u8; 24], hmac_key: &[u8], byte_offset: u64) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let decrypted_file_path = encrypted_file_path.as_os_str().to_str().ok_or(CryptError::PathError)?;
+
+        let data = self.read_encrypted_file(encrypted_file_path)?;
+        let (file_header, encrypted_data) = self.open_file_as(&data, CipherMode::XChaCha20, hmac_key)?;
+        let nonce = file_nonce(&file_header, Some(nonce))?;
+        // The offset counts plaintext bytes, which don't line up with a compressed payload
+        if file_header.compressed {
+            return Err(CryptError::NotImplemented("resuming the decryption of compressed files"));
+        }
+        let decrypt_file_path = header::restore_extension(self.generate_original_filename(decrypted_file_path).await, file_header.extension);
+
+        // The partial output must end exactly where decryption resumes
+        let offset = usize::try_from(byte_offset).ok()
+            .filter(|offset| *offset <= encrypted_data.len())
+            .ok_or(CryptError::InvalidParameters)?;
+        let written = fs::metadata(&decrypt_file_path).map(|metadata| metadata.len()).unwrap_or(0);
+        if written != byte_offset {
+            return Err(CryptError::InvalidParameters);
+        }
 
-        fs::write(&decrypt_file_path, &decrypted_data).map_err(|_| CryptError::WriteError)?;
+        let mut decrypted_data = encrypted_data[offset..].to_vec();
+        let mut cipher = XChaCha20::new(GenericArray::from_slice(key.as_bytes()), GenericArray::from_slice(nonce));
+        cipher.seek(byte_offset);
+        cipher.apply_keystream(&mut decrypted_data);
 
-        println!("Decryption completed and file written to {:?}", decrypt_file_path);
+        // XChaCha20 adds no padding, so the remaining plaintext is appended as is, trailing zeros included
+        let mut output = fs::OpenOptions::new().create(true).append(true).open(&decrypt_file_path)
+            .map_err(|_| CryptError::WriteError)?;
+        output.write_all(&decrypted_data).map_err(|_| CryptError::WriteError)?;
         Ok(decrypted_data)
     }
 
     pub async fn decrypt_msg_xchacha20(&self, encrypted_data_with_hmac: &[u8], key: &dyn SharedSecret, nonce: &[u8; 24], hmac_key: &[u8], safe: bool) -> Result<String, CryptError> {
-        let encrypted_data = self.verify_hmac(hmac_key, encrypted_data_with_hmac, 64).unwrap();
+        check_distinct_keys(key.as_bytes(), hmac_key)?;
+        let encrypted_data = envelope::verify_hmac_at(hmac_key, encrypted_data_with_hmac, 64, self.tag_position)?;
         let decrypted_data = self.decrypt_data_xchacha20(&encrypted_data, &nonce, key.as_bytes()).await?;
         let decrypted_str = String::from_utf8(decrypted_data)
             .map_err(|_| CryptError::Utf8Error)?;
         if safe {
-            let message_file = fs::File::create("./message.txt");
-            write!(message_file.unwrap(), "{}", &decrypted_str).unwrap();
+            let mut message_file = fs::File::create(&self.message_path)?;
+            write!(message_file, "{}", &decrypted_str)?;
         }
-        println!("{}", &decrypted_str);
+        tracing::debug!("decrypted a message of {} bytes", decrypted_str.len());
         Ok(decrypted_str)
     }
 }
\ No newline at end of file
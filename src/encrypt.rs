@@ -1,5 +1,5 @@
 use crate::keychain::*;
-use pqcrypto_classicmceliece::mceliece8192128::{self, *};
+use pqcrypto_classicmceliece::mceliece8192128;
 use pqcrypto_falcon::falcon1024::{self, *};
 use pqcrypto_traits::kem::{PublicKey as PublicKeyKem, SecretKey as SecKeyKem, SharedSecret as SharedSecretKem, Ciphertext as CiphertextKem};
 use hmac::{Hmac, Mac};
@@ -11,23 +11,34 @@ use std::{
     env::current_dir
 };
 use crate::{
+    compression,
+    envelope,
+    framing,
+    header,
+    kem::RuntimeKeychain,
     ActionTypeMceliece as ActionType,
+    CipherModeMceliece as CipherMode,
     EncryptMceliece as Encrypt,
     KeychainMceliece as Keychain, 
+    SignatureAlgorithmMceliece as SignatureAlgorithm,
 };
 use rand::{rngs::OsRng, RngCore};
+use zeroize::Zeroizing;
 use pqcrypto_traits::sign::{
     DetachedSignature as DetachedSignatureSign, PublicKey as PublicKeySign,
     SecretKey as SecretKeySign, SignedMessage as SignedMessageSign,
 };
-use byteorder::{BigEndian, WriteBytesExt};
  use crypt_guard_sign::{self, *};
 
+#[cfg(feature = "dilithium")]
+use pqcrypto_dilithium::dilithium5;
 #[cfg(feature = "xchacha20")]
 use chacha20::{
     XChaCha20, 
     cipher::{KeyIvInit, StreamCipher, StreamCipherSeek}
 };
+#[cfg(feature = "ctr")]
+use ctr::cipher::{KeyIvInit as _, StreamCipher as _};
 use std::iter::repeat;
 
 #[cfg(feature = "default")]
@@ -62,14 +73,25 @@ impl Encrypt {
         signed_message.to_owned()
     }
 
-    pub fn append_signature(data: &[u8], signature: Vec<u8>) -> Result<Vec<u8>, CryptError> {
-        let data_length = data.len() as u64;
-        let mut data_length_bytes = vec![];
-        data_length_bytes.write_u64::<BigEndian>(data_length).unwrap();
+    #[cfg(feature = "dilithium")]
+    pub fn generate_signature_dilithium(data: &[u8], sk: &dilithium5::SecretKey) -> Vec<u8> {
+        let signature = dilithium5::detached_sign(data, sk);
+        DetachedSignatureSign::as_bytes(&signature).to_owned()
+    }
+
+    // Signs the SHA-512 digest of the file, which is read in chunks instead of being loaded whole
+    pub fn sign_file_digest(file_path: &Path, sk: falcon1024::SecretKey) -> Result<Vec<u8>, CryptError> {
+        let digest = file_digest(file_path)?;
+        Ok(Self::generate_signature(&digest, sk))
+    }
 
-        let signed_data = [data_length_bytes, data.to_vec(), signature].concat();
+    #[deprecated(note = "only covers Falcon-1024 signatures, use append_signature_with_algorithm instead")]
+    pub fn append_signature(data: &[u8], signature: Vec<u8>) -> Result<Vec<u8>, CryptError> {
+        Self::append_signature_with_algorithm(data, SignatureAlgorithm::Falcon1024, signature)
+    }
 
-        Ok(signed_data)
+    pub fn append_signature_with_algorithm(data: &[u8], algorithm: SignatureAlgorithm, signature: Vec<u8>) -> Result<Vec<u8>, CryptError> {
+        Ok(framing::write_signed(data, algorithm, &signature))
     }
 
 
@@ -78,52 +100,84 @@ impl Encrypt {
         "-----BEGIN ENCRYPTED MESSAGE-----\n{}\n-----END ENCRYPTED MESSAGE-----",
             hex::encode(&message)
         );
-        let _ = fs::write("./message.enc", &hex_message)
-            .map_err(|_| CryptError::WriteError);
+        if let Err(err) = fs::write("./message.enc", &hex_message) {
+            tracing::warn!("could not save the encrypted message to ./message.enc: {}", err);
+        }
 
         Ok(())
     }
 
+    // The AES and HMAC keys are both derived from the encapsulated shared secret with DerivedKeys
     pub async fn encrypt(
         &self, 
         public_key_path: PathBuf,
         encrypt: &str,
         action: ActionType,
+        nonce: Option<&[u8; 24]>,
+    ) -> Result<Vec<u8>, CryptError> {
+        let shared_secret = Self::encapsulate_for(public_key_path).await?;
+        let keys = DerivedKeys::from_shared_secret(&shared_secret);
+        self.encrypt_action(&keys.aes_key_as_shared_secret(), encrypt, action, &keys.hmac_key, nonce).await
+    }
+
+    // Same as encrypt, with the shared secret used as the AES key and the HMAC key supplied by the caller
+    pub async fn encrypt_with_hmac_key(
+        &self,
+        public_key_path: PathBuf,
+        encrypt: &str,
+        action: ActionType,
         hmac_key: &[u8],
         nonce: Option<&[u8; 24]>,
     ) -> Result<Vec<u8>, CryptError> {
+        let shared_secret = Self::encapsulate_for(public_key_path).await?;
+        self.encrypt_action(&shared_secret, encrypt, action, hmac_key, nonce).await
+    }
+
+    async fn encapsulate_for(public_key_path: PathBuf) -> Result<mceliece8192128::SharedSecret, CryptError> {
         let mut keychain = Keychain::new().unwrap();
 
         // Load the public key from the given path
         let public_key = keychain.load_public_key(public_key_path).await?;
 
         // Encapsulate using the public key
-        let (shared_secret, ciphertext) = mceliece8192128::encapsulate(&public_key);
+        let (shared_secret, _ciphertext) = generate_encapsulation(&public_key);
+        Ok(shared_secret)
+    }
+
+    async fn encrypt_action(
+        &self,
+        shared_secret: &dyn SharedSecretKem,
+        encrypt: &str,
+        action: ActionType,
+        hmac_key: &[u8],
+        nonce: Option<&[u8; 24]>,
+    ) -> Result<Vec<u8>, CryptError> {
+        // A nonce asks for XChaCha20, which must not silently fall back to AES
+        #[cfg(not(feature = "xchacha20"))]
+        if nonce.is_some() {
+            return Err(CryptError::NotImplemented("xchacha20 feature not enabled"));
+        }
 
         match action {
             ActionType::FileAction => {
                 let path = PathBuf::from(encrypt);
-                println!("Encrypting file...");
-
-                #[cfg(feature = "default")]
-                let encrypted_data = self.encrypt_file(path.clone(), &shared_secret, hmac_key).await?;
-                #[cfg(feature = "xchacha20")]
-                if nonce != None {
-                    let nonce: &[u8; 24] = nonce.unwrap();
-                    let encrypted_data = self.encrypt_file_xchacha20(path, &shared_secret, nonce, hmac_key).await?;
+                tracing::debug!("encrypting file {}", encrypt);
+
+                // Only one of the two writes the .enc file
+                match nonce {
+                    #[cfg(feature = "xchacha20")]
+                    Some(nonce) => self.encrypt_file_xchacha20(path, shared_secret, nonce, hmac_key).await,
+                    _ => self.encrypt_file(path, shared_secret, hmac_key).await,
                 }
-                Ok(encrypted_data)
             },
             ActionType::MessageAction => {
-                println!("Encrypting message...\n");
-
-                #[cfg(feature = "default")]
-                let encrypted_data = self.encrypt_msg(encrypt, &shared_secret, hmac_key).await?;
-                #[cfg(feature = "xchacha20")]
-                if nonce != None {
-                    let nonce: &[u8; 24] = nonce.unwrap();
-                    let encrypted_data = self.encrypt_msg_xchacha20(encrypt, &shared_secret, nonce, hmac_key).await?;
-                }
+                tracing::debug!("encrypting message");
+
+                let encrypted_data = match nonce {
+                    #[cfg(feature = "xchacha20")]
+                    Some(nonce) => self.encrypt_msg_xchacha20(encrypt, shared_secret, nonce, hmac_key).await?,
+                    _ => self.encrypt_msg(encrypt, shared_secret, hmac_key).await?,
+                };
 
                 self.save_encrypted_message(&encrypted_data, PathBuf::from("./message.enc")).await?;
                 Ok(encrypted_data)
@@ -139,6 +193,25 @@ impl Encrypt {
 impl Encrypt {
     #[cfg(feature = "default")]
     pub async fn encrypt_data(&self, data: &[u8], key: &[u8], hmac_secret: &[u8]) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key, hmac_secret)?;
+        let encrypted_data = Self::encrypt_blocks(data, key);
+        
+        let hmac = Self::generate_hmac(hmac_secret, &encrypted_data);
+        let encrypted_and_signed_data = Self::append_hmac(encrypted_data, hmac);
+        
+        Ok(encrypted_and_signed_data)
+    }
+
+    // The associated data is authenticated by the HMAC but not encrypted or stored
+    pub async fn encrypt_data_with_aad(&self, data: &[u8], key: &[u8], hmac_secret: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key, hmac_secret)?;
+        let encrypted_data = Self::encrypt_blocks(data, key);
+
+        let hmac = envelope::generate_hmac_with_aad(hmac_secret, aad, &encrypted_data)?;
+        Ok(Self::append_hmac(encrypted_data, hmac))
+    }
+
+    fn encrypt_blocks(data: &[u8], key: &[u8]) -> Vec<u8> {
         let block_size = 16;
         let mut padded_data = data.to_vec();
 
@@ -155,28 +228,171 @@ impl Encrypt {
             cipher.encrypt_block(&mut block);
             encrypted_chunk.copy_from_slice(&block);
         }
-        
-        let hmac = Self::generate_hmac(hmac_secret, &encrypted_data);
-        let encrypted_and_signed_data = Self::append_hmac(encrypted_data, hmac);
-        
-        Ok(encrypted_and_signed_data)
+        encrypted_data
+    }
+
+    pub async fn encrypt_with_key(&self, data: &[u8], aes_key: &[u8; 32], hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        self.encrypt_data(data, aes_key, hmac_key).await
+    }
+
+    pub async fn encrypt_data_derived(&self, data: &[u8], shared_secret: &dyn SharedSecretKem) -> Result<Vec<u8>, CryptError> {
+        let keys = DerivedKeys::from_shared_secret(shared_secret);
+        self.encrypt_with_key(data, &keys.aes_key, &keys.hmac_key).await
+    }
+
+    // Same as encrypt_data_derived, with the shared secret of a keychain of any variant
+    pub async fn encrypt_data_runtime(&self, data: &[u8], keychain: &RuntimeKeychain) -> Result<Vec<u8>, CryptError> {
+        let shared_secret = keychain.shared_secret.as_ref().ok_or(CryptError::MissingSharedSecret)?;
+        let keys = DerivedKeys::from_secret_bytes(shared_secret);
+        self.encrypt_with_key(data, &keys.aes_key, &keys.hmac_key).await
     }
 
     pub async fn encrypt_file(&self, file_path: PathBuf, shared_secret: &dyn SharedSecretKem, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
-        let data = fs::read(&file_path).map_err(|_| CryptError::IOError)?;
-        let encrypted_data = self.encrypt_data(&data, shared_secret.as_bytes(), hmac_key).await?;
+        let (_, encrypted_data) = self.write_encrypted_file(&file_path, shared_secret.as_bytes(), hmac_key).await?;
+        Ok(encrypted_data)
+    }
 
-        let mut encrypted_file_path = file_path.clone();
-        let unique_encrypted_file_path = Keychain::generate_unique_filename(encrypted_file_path.as_os_str().to_str().expect("REASON"), "enc");
+    // Encapsulates for the recipient and returns the written path together with the ciphertext they need to decapsulate
+    pub async fn encrypt_file_for(&self, file_path: PathBuf, recipient_public_key: &mceliece8192128::PublicKey, hmac_key: &[u8]) -> Result<(PathBuf, mceliece8192128::Ciphertext), CryptError> {
+        let (shared_secret, ciphertext) = generate_encapsulation(recipient_public_key);
+        let (enc_file_path, _) = self.write_encrypted_file(&file_path, shared_secret.as_bytes(), hmac_key).await?;
+        Ok((enc_file_path, ciphertext))
+    }
+
+    // A self-contained bundle for a single recipient: the KEM ciphertext, the cipher id, the nonce length and
+    // nonce (empty for AES), the encrypted payload and an HMAC over all of it. Both keys are derived from a
+    // fresh encapsulation, so nothing besides the recipient's secret key is needed to open it
+    pub async fn encrypt_bundle(&self, data: &[u8], recipient_public_key: &mceliece8192128::PublicKey) -> Result<Vec<u8>, CryptError> {
+        let (shared_secret, ciphertext) = generate_encapsulation(recipient_public_key);
+        let keys = DerivedKeys::from_shared_secret(&shared_secret);
+
+        let mut bundle = ciphertext.as_bytes().to_vec();
+        bundle.push(CipherMode::Aes.id());
+        bundle.push(0);
+        bundle.extend_from_slice(&Self::encrypt_blocks(data, &keys.aes_key));
+
+        let hmac = Self::generate_hmac(&keys.hmac_key, &bundle);
+        Ok(Self::append_hmac(bundle, hmac))
+    }
+
+    // The payload is encrypted once under a random content key, which is wrapped separately for every recipient
+    pub async fn encrypt_file_multi(&self, file_path: PathBuf, recipients: &[mceliece8192128::PublicKey], hmac_key: &[u8]) -> Result<(PathBuf, Vec<Vec<u8>>), CryptError> {
+        if recipients.is_empty() {
+            return Err(CryptError::InvalidParameters);
+        }
+
+        let mut content_key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(content_key.as_mut());
+        let (enc_file_path, _) = self.write_encrypted_file(&file_path, content_key.as_ref(), hmac_key).await?;
+
+        let wrapped_keys = recipients.iter()
+            .map(|public_key| wrap_content_key(public_key, &content_key))
+            .collect();
+        Ok((enc_file_path, wrapped_keys))
+    }
+
+    async fn write_encrypted_file(&self, file_path: &Path, key: &[u8], hmac_key: &[u8]) -> Result<(PathBuf, Vec<u8>), CryptError> {
+        let (data, compressed) = compression::compress_file_data(fs::read(file_path)?)?;
+        let encrypted_data = self.encrypt_file_data(&data, key, hmac_key, header::file_extension(file_path), compressed).await?;
+
+        let unique_encrypted_file_path = Keychain::generate_unique_filename(file_path.to_str().ok_or(CryptError::PathError)?, "enc")?;
         let enc_file_path = PathBuf::from(unique_encrypted_file_path);
         fs::write(&enc_file_path, &encrypted_data).map_err(|_| CryptError::WriteError)?;
-        Ok(encrypted_data) // Return the path of the encrypted file
+        Ok((enc_file_path, encrypted_data))
+    }
+
+    // The file header is the associated data of the HMAC, so the cipher id, the compression flag and the
+    // extension can't be altered without failing verification
+    pub(crate) async fn encrypt_file_data(&self, data: &[u8], key: &[u8], hmac_key: &[u8], extension: Option<&str>, compressed: bool) -> Result<Vec<u8>, CryptError> {
+        let mut file_data = header::encode_file_header(CipherMode::Aes, extension, compressed, None)?;
+        let encrypted_data = self.encrypt_data_with_aad(data, key, hmac_key, &file_data).await?;
+        file_data.extend_from_slice(&encrypted_data);
+        Ok(file_data)
+    }
+
+    // Tar-then-encrypt of a whole directory tree, written next to it as <dir>.tar.enc
+    #[cfg(feature = "tar")]
+    pub async fn encrypt_directory(&self, dir_path: &Path, shared_secret: &dyn SharedSecretKem, hmac_key: &[u8]) -> Result<PathBuf, CryptError> {
+        if !dir_path.is_dir() {
+            return Err(CryptError::PathError);
+        }
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_dir_all(".", dir_path)?;
+        let mut archive = builder.into_inner()?;
+        archive.push(ARCHIVE_END_MARKER);
+
+        let encrypted_data = self.encrypt_file_data(&archive, shared_secret.as_bytes(), hmac_key, None, false).await?;
+
+        let base_path = format!("{}.tar", dir_path.to_str().ok_or(CryptError::PathError)?);
+        let encrypted_file_path = Keychain::generate_unique_filename(&base_path, "enc")?;
+        fs::write(&encrypted_file_path, &encrypted_data)?;
+        Ok(PathBuf::from(encrypted_file_path))
     }
 
     pub async fn encrypt_msg(&self, message: &str, shared_secret: &dyn SharedSecretKem, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
         let data = message.as_bytes();
         self.encrypt_data(data, shared_secret.as_bytes(), hmac_key).await
     }
+
+    // Sign-then-encrypt: the Falcon signature is framed with the plaintext and both are encrypted together
+    pub async fn seal_signed(&self, message: &[u8], keychain: &Keychain, signing_key: falcon1024::SecretKey, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        keychain.check_usage(KeyUsage::Both)?;
+        let shared_secret = keychain.shared_secret.as_ref().ok_or(CryptError::MissingSharedSecret)?;
+
+        let signed_data = Self::sign_for_sealing(message, signing_key)?;
+        self.encrypt_data(&signed_data, shared_secret.as_bytes(), hmac_key).await
+    }
+
+    pub async fn seal_signed_file(&self, file_path: PathBuf, shared_secret: &dyn SharedSecretKem, signing_key: &falcon1024::SecretKey, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        let signed_data = Self::sign_for_sealing(&fs::read(&file_path)?, *signing_key)?;
+        let encrypted_data = self.encrypt_file_data(&signed_data, shared_secret.as_bytes(), hmac_key, header::file_extension(&file_path), false).await?;
+
+        let unique_encrypted_file_path = Keychain::generate_unique_filename(file_path.as_os_str().to_str().ok_or(CryptError::PathError)?, "enc")?;
+        fs::write(PathBuf::from(unique_encrypted_file_path), &encrypted_data).map_err(|_| CryptError::WriteError)?;
+        Ok(encrypted_data)
+    }
+
+    // seal_signed_file with the shared secret taken from a keychain whose usage allows signing
+    pub async fn seal_signed_file_with_keychain(&self, file_path: PathBuf, keychain: &Keychain, signing_key: &falcon1024::SecretKey, hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
+        keychain.check_usage(KeyUsage::Both)?;
+        let shared_secret = keychain.shared_secret.as_ref().ok_or(CryptError::MissingSharedSecret)?;
+        self.seal_signed_file(file_path, shared_secret, signing_key, hmac_key).await
+    }
+
+    fn sign_for_sealing(message: &[u8], signing_key: falcon1024::SecretKey) -> Result<Vec<u8>, CryptError> {
+        let signature = Self::generate_signature(message, signing_key);
+        Self::append_signature_with_algorithm(message, SignatureAlgorithm::Falcon1024, signature)
+    }
+}
+
+// The tar stream ends in zero blocks, which the AES padding strip would eat, so a non-zero end marker follows it
+#[cfg(feature = "tar")]
+pub(crate) const ARCHIVE_END_MARKER: u8 = 1;
+
+#[cfg(feature = "ctr")]
+pub(crate) type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+#[cfg(feature = "ctr")]
+pub(crate) const CTR_NONCE_LEN: usize = 16;
+
+#[cfg(feature = "ctr")]
+impl Encrypt {
+    // The random initial counter block is stored ahead of the ciphertext and covered by the HMAC. CTR is a
+    // stream cipher, so nothing is padded and trailing zeros in the data survive decryption
+    pub async fn encrypt_data_ctr(&self, data: &[u8], key: &[u8], hmac_secret: &[u8]) -> Result<Vec<u8>, CryptError> {
+        check_distinct_keys(key, hmac_secret)?;
+        let mut nonce = [0u8; CTR_NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut cipher = Aes256Ctr::new_from_slices(key, &nonce)
+            .map_err(|_| CryptError::InvalidKeyLength { expected: 32, actual: key.len() })?;
+
+        let mut encrypted_data = nonce.to_vec();
+        encrypted_data.extend_from_slice(data);
+        cipher.apply_keystream(&mut encrypted_data[CTR_NONCE_LEN..]);
+
+        let hmac = Self::generate_hmac(hmac_secret, &encrypted_data);
+        Ok(Self::append_hmac(encrypted_data, (*hmac).to_vec()))
+    }
 }
 
 #[cfg(feature = "xchacha20")]
@@ -186,13 +402,44 @@ pub fn generate_nonce() -> [u8; 24] {
     nonce
 }
 
+// Development aid that remembers every (key, nonce) pair it is shown, keys are kept only as a SHA-512 digest
+#[cfg(feature = "xchacha20")]
+#[derive(Default)]
+pub struct NonceTracker {
+    seen: std::sync::Mutex<std::collections::HashSet<([u8; 64], [u8; 24])>>,
+}
+
+#[cfg(feature = "xchacha20")]
+impl NonceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, key: &[u8], nonce: &[u8; 24]) -> Result<(), CryptError> {
+        let key_digest: [u8; 64] = <Sha512 as sha2::Digest>::digest(key).into();
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if seen.insert((key_digest, *nonce)) {
+            Ok(())
+        } else {
+            Err(CryptError::NonceReuse)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 #[cfg(feature = "xchacha20")]
 impl Encrypt {
     #[cfg(feature = "xchacha20")]
     pub async fn encrypt_data_xchacha20(&self, data: &[u8], key: &[u8], nonce: &[u8; 24], hmac_secret: &[u8]) -> Result<Vec<u8>, CryptError> { 
-        let mut cipher = XChaCha20::new(GenericArray::from_slice(key), GenericArray::from_slice(nonce));
-        let mut encrypted_data = data.to_vec();
-        cipher.apply_keystream(&mut encrypted_data);
+        check_distinct_keys(key, hmac_secret)?;
+        let encrypted_data = Self::apply_xchacha20(data, key, nonce)?;
 
         let hmac = Self::generate_hmac(hmac_secret, &encrypted_data);
         let encrypted_and_signed_data = Self::append_hmac(encrypted_data, (*hmac).to_vec());
@@ -200,12 +447,35 @@ impl Encrypt {
         Ok(encrypted_and_signed_data)
     }
 
+    fn apply_xchacha20(data: &[u8], key: &[u8], nonce: &[u8; 24]) -> Result<Vec<u8>, CryptError> {
+        // An all-zero nonce is almost always an uninitialized buffer rather than a random one
+        if nonce.iter().all(|&byte| byte == 0) {
+            return Err(CryptError::InvalidParameters);
+        }
+        let mut cipher = XChaCha20::new(GenericArray::from_slice(key), GenericArray::from_slice(nonce));
+        let mut encrypted_data = data.to_vec();
+        cipher.apply_keystream(&mut encrypted_data);
+        Ok(encrypted_data)
+    }
+
+    // Same as encrypt_data_xchacha20, but fails with NonceReuse when the tracker has seen the nonce under this key before
+    pub async fn encrypt_data_xchacha20_tracked(&self, data: &[u8], key: &[u8], nonce: &[u8; 24], hmac_secret: &[u8], tracker: &NonceTracker) -> Result<Vec<u8>, CryptError> {
+        tracker.record(key, nonce)?;
+        self.encrypt_data_xchacha20(data, key, nonce, hmac_secret).await
+    }
+
     pub async fn encrypt_file_xchacha20(&self, file_path: PathBuf, shared_secret: &dyn SharedSecretKem, nonce: &[u8; 24], hmac_key: &[u8]) -> Result<Vec<u8>, CryptError> {
-        let data = fs::read(&file_path).map_err(|_| CryptError::IOError)?;
-        let encrypted_data = self.encrypt_data_xchacha20(&data, shared_secret.as_bytes(), nonce, hmac_key).await?;
+        check_distinct_keys(shared_secret.as_bytes(), hmac_key)?;
+        let (data, compressed) = compression::compress_file_data(fs::read(&file_path)?)?;
+        // As on the AES path the header, which also records the nonce, is authenticated along with the ciphertext
+        let mut encrypted_data = header::encode_file_header(CipherMode::XChaCha20, header::file_extension(&file_path), compressed, Some(nonce))?;
+        let ciphertext = Self::apply_xchacha20(&data, shared_secret.as_bytes(), nonce)?;
+        let hmac = envelope::generate_hmac_with_aad(hmac_key, &encrypted_data, &ciphertext)?;
+        encrypted_data.extend_from_slice(&ciphertext);
+        encrypted_data.extend_from_slice(&hmac);
 
         let mut encrypted_file_path = file_path.clone();
-        let unique_encrypted_file_path = Keychain::generate_unique_filename(encrypted_file_path.as_os_str().to_str().expect("REASON"), "enc");
+        let unique_encrypted_file_path = Keychain::generate_unique_filename(encrypted_file_path.as_os_str().to_str().expect("REASON"), "enc")?;
         let enc_file_path = PathBuf::from(unique_encrypted_file_path);
         fs::write(&enc_file_path, &encrypted_data).map_err(|_| CryptError::WriteError)?;
         Ok(encrypted_data) // Return the path of the encrypted file
@@ -217,9 +487,3 @@ impl Encrypt {
         encrypted_msg
     }
 }
-
-impl From<io::Error> for CryptError {
-    fn from(_: io::Error) -> Self {
-        CryptError::IOError
-    }
-}
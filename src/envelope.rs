@@ -0,0 +1,245 @@
+use crate::error::CryptError;
+use alloc::vec::Vec;
+use aes::{
+    cipher::{
+        BlockDecrypt,
+        consts::U16,
+        generic_array::GenericArray,
+        inout::InOutBuf,
+        KeyInit
+    },
+    Aes256
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use subtle::ConstantTimeEq;
+
+// The symmetric envelope without std: AES-256 blocks followed by an HMAC-SHA512 tag
+pub const HMAC_LEN: usize = 64;
+
+// Tags are only ever shortened when a DecryptBuilder asked for it, and never below 128 bits
+pub const MIN_TRUNCATED_HMAC_LEN: usize = 16;
+
+// Where the tag sits relative to the ciphertext, some older exports put it in front
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TagPosition {
+    Prefix,
+    #[default]
+    Suffix,
+}
+
+// Splits into (data, tag)
+pub fn split_tag(data_with_hmac: &[u8], hmac_len: usize, position: TagPosition) -> Result<(&[u8], &[u8]), CryptError> {
+    if data_with_hmac.len() < hmac_len {
+        return Err(CryptError::HmacShortData);
+    }
+
+    Ok(match position {
+        TagPosition::Prefix => {
+            let (tag, data) = data_with_hmac.split_at(hmac_len);
+            (data, tag)
+        }
+        TagPosition::Suffix => data_with_hmac.split_at(data_with_hmac.len() - hmac_len),
+    })
+}
+
+pub fn verify_hmac(key: &[u8], data_with_hmac: &[u8], hmac_len: usize) -> Result<Vec<u8>, CryptError> {
+    verify_hmac_at(key, data_with_hmac, hmac_len, TagPosition::Suffix)
+}
+
+pub fn verify_hmac_at(key: &[u8], data_with_hmac: &[u8], hmac_len: usize, position: TagPosition) -> Result<Vec<u8>, CryptError> {
+    let (data, hmac) = split_tag(data_with_hmac, hmac_len, position)?;
+    check_hmac(key, data, hmac)?;
+
+    Ok(data.to_vec())
+}
+
+// Only the full HMAC_LEN byte tag verifies
+pub fn check_hmac(key: &[u8], data: &[u8], tag: &[u8]) -> Result<(), CryptError> {
+    let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(key)
+        .map_err(|_| CryptError::HmacKeyErr)?;
+    mac.update(data);
+    mac.verify_slice(tag).map_err(|_| CryptError::HmacVerificationError)
+}
+
+// Accepts tags shortened to their leftmost hmac_len bytes. The minimum is checked here as well as in
+// DecryptBuilder::build, since Decrypt's fields are public and can be set without the builder
+#[cfg(feature = "std")]
+pub(crate) fn verify_truncated_hmac_at(key: &[u8], data_with_hmac: &[u8], aad: Option<&[u8]>, hmac_len: usize, position: TagPosition) -> Result<Vec<u8>, CryptError> {
+    if !(MIN_TRUNCATED_HMAC_LEN..=HMAC_LEN).contains(&hmac_len) {
+        return Err(CryptError::InvalidParameters);
+    }
+    let (data, tag) = split_tag(data_with_hmac, hmac_len, position)?;
+    let mut mac = new_mac(key, aad)?;
+    mac.update(data);
+    mac.verify_truncated_left(tag).map_err(|_| CryptError::HmacVerificationError)?;
+
+    Ok(data.to_vec())
+}
+
+// Incremental counterpart of verify_hmac for data that is streamed through in chunks
+pub struct HmacVerifier {
+    mac: Hmac<Sha512>,
+}
+
+impl HmacVerifier {
+    pub fn new(key: &[u8]) -> Result<Self, CryptError> {
+        Ok(Self { mac: new_mac(key, None)? })
+    }
+
+    // Counterpart of verify_hmac_with_aad
+    pub fn with_aad(key: &[u8], aad: &[u8]) -> Result<Self, CryptError> {
+        Ok(Self { mac: new_mac(key, Some(aad))? })
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.mac.update(chunk);
+    }
+
+    pub fn finalize(self, expected_tag: &[u8]) -> Result<(), CryptError> {
+        self.mac.verify_slice(expected_tag).map_err(|_| CryptError::HmacVerificationError)
+    }
+}
+
+// Without associated data this is the plain HMAC, which is not the same as an HMAC over empty associated data
+fn new_mac(key: &[u8], aad: Option<&[u8]>) -> Result<Hmac<Sha512>, CryptError> {
+    match aad {
+        Some(aad) => mac_with_aad(key, aad),
+        None => <Hmac<Sha512> as Mac>::new_from_slice(key).map_err(|_| CryptError::HmacKeyErr),
+    }
+}
+
+// Associated data is authenticated as its length (u64, big endian) and bytes ahead of the ciphertext
+fn mac_with_aad(key: &[u8], aad: &[u8]) -> Result<Hmac<Sha512>, CryptError> {
+    let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(key)
+        .map_err(|_| CryptError::HmacKeyErr)?;
+    mac.update(&(aad.len() as u64).to_be_bytes());
+    mac.update(aad);
+    Ok(mac)
+}
+
+pub fn generate_hmac_with_aad(key: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptError> {
+    let mut mac = mac_with_aad(key, aad)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+pub fn verify_hmac_with_aad(key: &[u8], data_with_hmac: &[u8], aad: &[u8], hmac_len: usize) -> Result<Vec<u8>, CryptError> {
+    verify_hmac_with_aad_at(key, data_with_hmac, aad, hmac_len, TagPosition::Suffix)
+}
+
+pub fn verify_hmac_with_aad_at(key: &[u8], data_with_hmac: &[u8], aad: &[u8], hmac_len: usize, position: TagPosition) -> Result<Vec<u8>, CryptError> {
+    let (data, hmac) = split_tag(data_with_hmac, hmac_len, position)?;
+    let mut mac = mac_with_aad(key, aad)?;
+    mac.update(data);
+    mac.verify_slice(hmac).map_err(|_| CryptError::HmacVerificationError)?;
+
+    Ok(data.to_vec())
+}
+
+pub fn decrypt_data(data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptError> {
+    let mut decrypted_data = Vec::new();
+    decrypt_data_into(data, key, &mut decrypted_data)?;
+    Ok(decrypted_data)
+}
+
+// Reuses the caller's buffer, which is cleared first and only grows when a larger message comes along
+pub fn decrypt_data_into(data: &[u8], key: &[u8], out: &mut Vec<u8>) -> Result<(), CryptError> {
+    if key.len() != 32 {
+        return Err(CryptError::InvalidParameters);
+    }
+    decrypt_with_cipher_into(&new_cipher(key), data, out)
+}
+
+fn decrypt_with_cipher_into(cipher: &Aes256, data: &[u8], out: &mut Vec<u8>) -> Result<(), CryptError> {
+    if !data.len().is_multiple_of(16) {
+        return Err(CryptError::InvalidParameters);
+    }
+
+    out.clear();
+    out.extend_from_slice(data);
+    decrypt_blocks_in_place(cipher, out);
+
+    // Remove padding if present
+    while out.last() == Some(&0) {
+        out.pop();
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "std"))]
+std::thread_local! {
+    pub(crate) static KEY_SCHEDULES: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+// Runs the AES key schedule, tests count the calls to check that sessions only do it once
+fn new_cipher(key: &[u8]) -> Aes256 {
+    #[cfg(all(test, feature = "std"))]
+    KEY_SCHEDULES.with(|count| count.set(count.get() + 1));
+    Aes256::new(GenericArray::from_slice(key))
+}
+
+// Keeps the expanded AES key and the keyed HMAC around for decrypting many messages under the same keys
+#[derive(Clone)]
+pub struct DecryptSession {
+    cipher: Aes256,
+    mac: Hmac<Sha512>,
+}
+
+impl DecryptSession {
+    pub fn new(key: &[u8], hmac_key: &[u8]) -> Result<Self, CryptError> {
+        if key.len() != 32 {
+            return Err(CryptError::InvalidKeyLength { expected: 32, actual: key.len() });
+        }
+        if bool::from(key.ct_eq(hmac_key)) {
+            return Err(CryptError::IdenticalKeys);
+        }
+        let mac = <Hmac<Sha512> as Mac>::new_from_slice(hmac_key)
+            .map_err(|_| CryptError::HmacKeyErr)?;
+        Ok(Self { cipher: new_cipher(key), mac })
+    }
+
+    // Same output as the free decrypt_data, without the tag check
+    pub fn decrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, CryptError> {
+        let mut decrypted_data = Vec::new();
+        self.decrypt_data_into(data, &mut decrypted_data)?;
+        Ok(decrypted_data)
+    }
+
+    pub fn decrypt_data_into(&self, data: &[u8], out: &mut Vec<u8>) -> Result<(), CryptError> {
+        decrypt_with_cipher_into(&self.cipher, data, out)
+    }
+
+    // Verifies the trailing HMAC_LEN byte tag before decrypting
+    pub fn decrypt(&self, data_with_hmac: &[u8]) -> Result<Vec<u8>, CryptError> {
+        let (data, tag) = split_tag(data_with_hmac, HMAC_LEN, TagPosition::Suffix)?;
+        let mut mac = self.mac.clone();
+        mac.update(data);
+        mac.verify_slice(tag).map_err(|_| CryptError::HmacVerificationError)?;
+        self.decrypt_data(data)
+    }
+}
+
+// Leaves the zero padding in place, for callers that strip it themselves
+#[cfg(feature = "std")]
+pub(crate) fn decrypt_blocks(data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptError> {
+    if key.len() != 32 || !data.len().is_multiple_of(16) {
+        return Err(CryptError::InvalidParameters);
+    }
+    let mut decrypted_data = data.to_vec();
+    decrypt_blocks_in_place(&new_cipher(key), &mut decrypted_data);
+    Ok(decrypted_data)
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn decrypt_blocks_into(cipher: &Aes256, data: &[u8], decrypted_data: &mut [u8]) {
+    decrypted_data.copy_from_slice(data);
+    decrypt_blocks_in_place(cipher, decrypted_data);
+}
+
+// Hands all whole blocks to the cipher at once, which can then decrypt several blocks in parallel
+pub(crate) fn decrypt_blocks_in_place(cipher: &Aes256, buffer: &mut [u8]) {
+    let (blocks, _) = InOutBuf::from(buffer).into_chunks::<U16>();
+    cipher.decrypt_blocks_inout(blocks);
+}
@@ -0,0 +1,151 @@
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use crypt_guard_sign::SigningErr;
+
+#[derive(Debug)]
+pub enum CryptError {
+    IOError,
+    MessageExtractionError,
+    InvalidMessageFormat,
+    HexError(hex::FromHexError),
+    EncapsulationError,
+    DecapsulationError,
+    WriteError,
+    HmacVerificationError,
+    HmacShortData,
+    HmacKeyErr,
+    HexDecodingError(String),
+    UniqueFilenameFailed,
+    MissingSecretKey,
+    MissingPublicKey,
+    MissingCiphertext,
+    MissingSharedSecret,
+    MissingData,
+    InvalidParameters,
+    PathError,
+    Utf8Error,
+    SigningFailed,
+    SignatureVerificationFailed,
+    InvalidSignatureLength { expected: usize, got: usize },
+    InvalidSignature,
+    DecryptionFailed,
+    UsageNotPermitted,
+    WrongKeyType,
+    IdenticalKeys,
+    InvalidKeyLength { expected: usize, actual: usize },
+    FileTooLarge { size: u64, limit: u64 },
+    UnsupportedSignatureAlgorithm(u8),
+    NotImplemented(&'static str),
+    Cancelled,
+    NonceReuse,
+    FingerprintMismatch,
+    #[cfg(feature = "std")]
+    OutputExists(std::path::PathBuf),
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    #[cfg(feature = "keyring")]
+    Keyring(keyring::Error),
+}
+
+impl fmt::Display for CryptError {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+       match self {
+           CryptError::IOError => write!(f, "IO error occurred"),
+           CryptError::MessageExtractionError => write!(f, "Error extracting message"),
+           CryptError::InvalidMessageFormat => write!(f, "Invalid message format"),
+           CryptError::HexError(err) => write!(f, "Hex error: {}", err),
+           CryptError::EncapsulationError => write!(f, "Encapsulation error"),
+           CryptError::DecapsulationError => write!(f, "Decapsulation error"),
+           CryptError::WriteError => write!(f, "Write error"),
+           CryptError::HmacVerificationError => write!(f, "HMAC verification error"),
+           CryptError::HmacShortData => write!(f, "Data is too short for HMAC verification"),
+           CryptError::HmacKeyErr => write!(f, "HMAC can take key of any size"),
+           CryptError::HexDecodingError(err) => write!(f, "Hex decoding error: {}", err),
+           CryptError::UniqueFilenameFailed => write!(f, "Unique filename failed"),
+           CryptError::MissingSecretKey => write!(f, "Missing secret key"),
+           CryptError::MissingPublicKey => write!(f, "Missing public key"),
+           CryptError::MissingCiphertext => write!(f, "Missing ciphertext"),
+           CryptError::MissingSharedSecret => write!(f, "Missing shared secret"),
+           CryptError::MissingData => write!(f, "Missing data"),
+           CryptError::InvalidParameters => write!(f, "You provided Invalid parameters"),
+           CryptError::PathError => write!(f, "The provided path does not exist!"),
+           CryptError::Utf8Error => write!(f, "UTF-8 conversion error"),
+           CryptError::SigningFailed => write!(f, "Signing file using falcon 1024 failed!"),
+           CryptError::SignatureVerificationFailed => write!(f, "verification of signature using falcon 1024 failed!"),
+           CryptError::InvalidSignature => write!(f, "Signature not valid!"),
+           CryptError::InvalidSignatureLength { expected, got } => write!(f, "Invalid signature length: expected at most {} bytes, got {}", expected, got),
+           CryptError::DecryptionFailed => write!(f, "Decryption failed"),
+           CryptError::UsageNotPermitted => write!(f, "The key usage policy does not permit this operation"),
+           CryptError::WrongKeyType => write!(f, "The file contains a different key type than requested"),
+           CryptError::IdenticalKeys => write!(f, "The HMAC key must differ from the encryption key"),
+           CryptError::InvalidKeyLength { expected, actual } => write!(f, "Invalid key length: expected {} bytes, got {}", expected, actual),
+           CryptError::FileTooLarge { size, limit } => write!(f, "File is too large: {} bytes exceeds the limit of {} bytes", size, limit),
+           CryptError::UnsupportedSignatureAlgorithm(id) => write!(f, "Signature algorithm id {} is not supported here", id),
+           CryptError::NotImplemented(reason) => write!(f, "Not implemented: {}", reason),
+           CryptError::Cancelled => write!(f, "The operation was cancelled"),
+           CryptError::NonceReuse => write!(f, "The nonce was already used with this key"),
+           CryptError::FingerprintMismatch => write!(f, "The public key does not match the expected fingerprint"),
+           #[cfg(feature = "std")]
+           CryptError::OutputExists(path) => write!(f, "The output file {} already exists", path.display()),
+           #[cfg(feature = "std")]
+           CryptError::Io(err) => write!(f, "IO error: {}", err),
+           #[cfg(feature = "keyring")]
+           CryptError::Keyring(err) => write!(f, "OS keyring error: {}", err),
+       }
+   }
+}
+
+#[cfg(feature = "std")]
+impl Error for CryptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CryptError::Io(err) => Some(err),
+            #[cfg(feature = "keyring")]
+            CryptError::Keyring(err) => Some(err),
+            CryptError::HexError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<hex::FromHexError> for CryptError {
+    fn from(error: hex::FromHexError) -> Self {
+        CryptError::HexError(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for CryptError {
+    fn from(error: std::io::Error) -> Self {
+        CryptError::Io(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<SigningErr> for CryptError {
+    fn from(error: SigningErr) -> Self {
+        match error {
+            SigningErr::SignatureVerificationFailed => CryptError::SignatureVerificationFailed,
+            SigningErr::SigningMessageFailed => CryptError::SigningFailed,
+            SigningErr::InvalidSignature => CryptError::InvalidSignature,
+            SigningErr::SecretKeyMissing => CryptError::MissingSecretKey,
+            SigningErr::PublicKeyMissing => CryptError::MissingPublicKey,
+            SigningErr::IOError(err) => CryptError::Io(err),
+            SigningErr::HexDecodingError => CryptError::HexDecodingError(String::from("Signing key hex decoding failed")),
+            SigningErr::InvalidPublicKeyFormat
+            | SigningErr::InvalidSecretKeyFormat
+            | SigningErr::InvalidPublicKey
+            | SigningErr::InvalidSecretKey => CryptError::InvalidParameters,
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl From<keyring::Error> for CryptError {
+    fn from(error: keyring::Error) -> Self {
+        CryptError::Keyring(error)
+    }
+}
@@ -0,0 +1,72 @@
+use crate::error::CryptError;
+use crate::SignatureAlgorithmMceliece as SignatureAlgorithm;
+use alloc::vec::Vec;
+
+// Framed data is prefixed with its length as a big endian u64, whatever follows the frame is left to the caller
+const LENGTH_PREFIX_LEN: usize = 8;
+
+pub(crate) fn write_framed(data: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(LENGTH_PREFIX_LEN + data.len());
+    framed.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    framed.extend_from_slice(data);
+    framed
+}
+
+pub(crate) fn read_framed(input: &[u8]) -> Result<(&[u8], &[u8]), CryptError> {
+    if input.len() < LENGTH_PREFIX_LEN {
+        return Err(CryptError::InvalidMessageFormat);
+    }
+
+    let (prefix, rest) = input.split_at(LENGTH_PREFIX_LEN);
+    let data_length = u64::from_be_bytes(prefix.try_into().map_err(|_| CryptError::InvalidMessageFormat)?);
+    let data_length = usize::try_from(data_length).map_err(|_| CryptError::InvalidMessageFormat)?;
+    if data_length > rest.len() {
+        return Err(CryptError::InvalidMessageFormat);
+    }
+
+    Ok(rest.split_at(data_length))
+}
+
+impl SignatureAlgorithm {
+    pub fn id(&self) -> u8 {
+        match self {
+            SignatureAlgorithm::Falcon1024 => 0,
+            SignatureAlgorithm::Dilithium5 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, CryptError> {
+        match id {
+            0 => Ok(SignatureAlgorithm::Falcon1024),
+            1 => Ok(SignatureAlgorithm::Dilithium5),
+            _ => Err(CryptError::UnsupportedSignatureAlgorithm(id)),
+        }
+    }
+}
+
+// Signed data is the framed data, the format version, the one byte algorithm id and then the detached signature
+const SIGNED_FORMAT_VERSION: u8 = 1;
+
+// Signed data from before the version byte holds a bare Falcon-1024 signature right after the frame. Those
+// always begin with the header byte 0x30 + logn, which the version byte is chosen never to collide with
+const LEGACY_FALCON1024_HEADER: u8 = 0x3A;
+
+pub(crate) fn write_signed(data: &[u8], algorithm: SignatureAlgorithm, signature: &[u8]) -> Vec<u8> {
+    let mut signed_data = write_framed(data);
+    signed_data.push(SIGNED_FORMAT_VERSION);
+    signed_data.push(algorithm.id());
+    signed_data.extend_from_slice(signature);
+    signed_data
+}
+
+pub(crate) fn read_signed(input: &[u8]) -> Result<(SignatureAlgorithm, &[u8], &[u8]), CryptError> {
+    let (data, rest) = read_framed(input)?;
+    match rest.split_first() {
+        Some((&SIGNED_FORMAT_VERSION, tagged)) => {
+            let (&id, signature) = tagged.split_first().ok_or(CryptError::InvalidMessageFormat)?;
+            Ok((SignatureAlgorithm::from_id(id)?, data, signature))
+        },
+        Some((&LEGACY_FALCON1024_HEADER, _)) => Ok((SignatureAlgorithm::Falcon1024, data, rest)),
+        _ => Err(CryptError::InvalidMessageFormat),
+    }
+}
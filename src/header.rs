@@ -0,0 +1,137 @@
+use crate::keychain::CryptError;
+use crate::CipherModeMceliece as CipherMode;
+use std::path::Path;
+
+// Encrypted files start with the magic, the format version, the cipher id and the length of the
+// original file extension, which follows as UTF-8 right after this fixed part. The top bit of the
+// cipher id marks gzip compressed plaintext. From version 3 on the whole header is the associated data
+// of the file's HMAC, so none of it is acted upon before the tag has been verified, and XChaCha20 files
+// end it with their 24 byte nonce so they can be decrypted without being told the cipher
+pub const FILE_MAGIC: &[u8; 4] = b"CGMc";
+pub const FILE_FORMAT_VERSION: u8 = 3;
+pub const FILE_HEADER_LEN: usize = 7;
+
+// Version 2 headers have the same layout as version 3 but are not covered by the HMAC
+const V2_FORMAT_VERSION: u8 = 2;
+
+// Version 1 headers stop after the cipher id and carry no extension
+const V1_HEADER_LEN: usize = 6;
+
+const COMPRESSED_FLAG: u8 = 0x80;
+
+const NONCE_LEN: usize = 24;
+
+// The longest header any version can have, a full extension followed by a nonce
+#[cfg(feature = "mmap")]
+pub(crate) const MAX_FILE_HEADER_LEN: usize = FILE_HEADER_LEN + u8::MAX as usize + NONCE_LEN;
+
+pub(crate) struct FileHeader<'a> {
+    pub mode: CipherMode,
+    pub extension: Option<&'a str>,
+    pub compressed: bool,
+    // Only recorded by version 3 XChaCha20 headers
+    pub nonce: Option<&'a [u8; NONCE_LEN]>,
+    // The header bytes the HMAC covers as associated data, None for the version 1 and 2 headers written before that
+    pub authenticated: Option<&'a [u8]>,
+}
+
+impl CipherMode {
+    pub fn id(&self) -> u8 {
+        match self {
+            CipherMode::Aes => 0,
+            CipherMode::XChaCha20 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, CryptError> {
+        match id {
+            0 => Ok(CipherMode::Aes),
+            1 => Ok(CipherMode::XChaCha20),
+            _ => Err(CryptError::InvalidMessageFormat),
+        }
+    }
+}
+
+// The extension is later appended to an output path, so anything that could leave the file name is refused
+fn is_valid_extension(extension: &str) -> bool {
+    !extension.is_empty()
+        && extension.len() <= u8::MAX as usize
+        && !extension.contains(['.', '/', '\\', '\0'])
+}
+
+pub(crate) fn file_extension(path: &Path) -> Option<&str> {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .filter(|extension| is_valid_extension(extension))
+}
+
+// Appends the recorded extension unless the recovered file name already ends with it
+pub(crate) fn restore_extension(file_path: String, extension: Option<&str>) -> String {
+    match extension {
+        Some(extension) if !file_path.ends_with(&format!(".{}", extension)) => format!("{}.{}", file_path, extension),
+        _ => file_path,
+    }
+}
+
+// The nonce is only written for XChaCha20, which can't be decrypted without it
+pub(crate) fn encode_file_header(mode: CipherMode, extension: Option<&str>, compressed: bool, nonce: Option<&[u8; NONCE_LEN]>) -> Result<Vec<u8>, CryptError> {
+    let nonce = match (mode, nonce) {
+        (CipherMode::Aes, None) => &[][..],
+        (CipherMode::XChaCha20, Some(nonce)) => &nonce[..],
+        _ => return Err(CryptError::InvalidParameters),
+    };
+    let extension = extension.filter(|extension| is_valid_extension(extension)).unwrap_or("");
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN + extension.len() + nonce.len());
+    header.extend_from_slice(FILE_MAGIC);
+    header.push(FILE_FORMAT_VERSION);
+    header.push(if compressed { mode.id() | COMPRESSED_FLAG } else { mode.id() });
+    header.push(extension.len() as u8);
+    header.extend_from_slice(extension.as_bytes());
+    header.extend_from_slice(nonce);
+    Ok(header)
+}
+
+// Returns the header and the tagged ciphertext after it
+pub(crate) fn read_file_header(data: &[u8]) -> Result<(FileHeader<'_>, &[u8]), CryptError> {
+    if data.len() < V1_HEADER_LEN || &data[..4] != FILE_MAGIC {
+        return Err(CryptError::InvalidMessageFormat);
+    }
+    let mode = CipherMode::from_id(data[5] & !COMPRESSED_FLAG)?;
+
+    match data[4] {
+        1 => Ok((FileHeader { mode, extension: None, compressed: false, nonce: None, authenticated: None }, &data[V1_HEADER_LEN..])),
+        version @ (V2_FORMAT_VERSION | FILE_FORMAT_VERSION) if data.len() >= FILE_HEADER_LEN => {
+            let extension_end = FILE_HEADER_LEN + data[6] as usize;
+            if data.len() < extension_end {
+                return Err(CryptError::InvalidMessageFormat);
+            }
+            let extension = match &data[FILE_HEADER_LEN..extension_end] {
+                [] => None,
+                extension => {
+                    let extension = std::str::from_utf8(extension).map_err(|_| CryptError::InvalidMessageFormat)?;
+                    if !is_valid_extension(extension) {
+                        return Err(CryptError::InvalidMessageFormat);
+                    }
+                    Some(extension)
+                },
+            };
+            let (header_end, nonce) = match (version, mode) {
+                (FILE_FORMAT_VERSION, CipherMode::XChaCha20) => {
+                    let nonce = data.get(extension_end..extension_end + NONCE_LEN).ok_or(CryptError::InvalidMessageFormat)?;
+                    (extension_end + NONCE_LEN, Some(nonce.try_into().map_err(|_| CryptError::InvalidMessageFormat)?))
+                },
+                _ => (extension_end, None),
+            };
+            let (header, encrypted_data) = data.split_at(header_end);
+            let file_header = FileHeader {
+                mode,
+                extension,
+                compressed: data[5] & COMPRESSED_FLAG != 0,
+                nonce,
+                authenticated: (version == FILE_FORMAT_VERSION).then_some(header),
+            };
+            Ok((file_header, encrypted_data))
+        },
+        _ => Err(CryptError::InvalidMessageFormat),
+    }
+}
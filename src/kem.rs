@@ -0,0 +1,115 @@
+use alloc::{boxed::Box, vec::Vec};
+use pqcrypto_classicmceliece::{mceliece348864, mceliece460896, mceliece6688128, mceliece8192128};
+use pqcrypto_traits::kem::{Ciphertext as _, PublicKey as _, SecretKey as _, SharedSecret as _};
+use zeroize::Zeroizing;
+use crate::error::CryptError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    V348864,
+    V460896,
+    V6688128,
+    V8192128,
+}
+
+impl Variant {
+    pub fn kem(self) -> Box<dyn Kem> {
+        match self {
+            Variant::V348864 => Box::new(Mceliece348864),
+            Variant::V460896 => Box::new(Mceliece460896),
+            Variant::V6688128 => Box::new(Mceliece6688128),
+            Variant::V8192128 => Box::new(Mceliece8192128),
+        }
+    }
+}
+
+// Object safe counterpart of the pqcrypto KEM functions, keys and ciphertexts are passed as raw bytes
+pub trait Kem: Send + Sync {
+    fn variant(&self) -> Variant;
+    // Returns (public_key, secret_key)
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>);
+    // Returns (shared_secret, ciphertext)
+    fn encapsulate(&self, public_key: &[u8]) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), CryptError>;
+    fn decapsulate(&self, ciphertext: &[u8], secret_key: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptError>;
+}
+
+fn parse<T>(bytes: &[u8], expected: usize, from_bytes: fn(&[u8]) -> Result<T, pqcrypto_traits::Error>) -> Result<T, CryptError> {
+    from_bytes(bytes).map_err(|_| CryptError::InvalidKeyLength { expected, actual: bytes.len() })
+}
+
+macro_rules! impl_kem {
+    ($name:ident, $module:ident, $variant:ident) => {
+        struct $name;
+
+        impl Kem for $name {
+            fn variant(&self) -> Variant {
+                Variant::$variant
+            }
+
+            fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+                let (public_key, secret_key) = $module::keypair();
+                (public_key.as_bytes().to_vec(), Zeroizing::new(secret_key.as_bytes().to_vec()))
+            }
+
+            fn encapsulate(&self, public_key: &[u8]) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), CryptError> {
+                let public_key = parse(public_key, $module::public_key_bytes(), $module::PublicKey::from_bytes)?;
+                let (shared_secret, ciphertext) = $module::encapsulate(&public_key);
+                Ok((Zeroizing::new(shared_secret.as_bytes().to_vec()), ciphertext.as_bytes().to_vec()))
+            }
+
+            fn decapsulate(&self, ciphertext: &[u8], secret_key: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptError> {
+                let ciphertext = parse(ciphertext, $module::ciphertext_bytes(), $module::Ciphertext::from_bytes)?;
+                let secret_key = parse(secret_key, $module::secret_key_bytes(), $module::SecretKey::from_bytes)?;
+                let shared_secret = $module::decapsulate(&ciphertext, &secret_key);
+                Ok(Zeroizing::new(shared_secret.as_bytes().to_vec()))
+            }
+        }
+    };
+}
+
+impl_kem!(Mceliece348864, mceliece348864, V348864);
+impl_kem!(Mceliece460896, mceliece460896, V460896);
+impl_kem!(Mceliece6688128, mceliece6688128, V6688128);
+impl_kem!(Mceliece8192128, mceliece8192128, V8192128);
+
+// Keychain whose parameter set is picked at runtime, the components are kept as bytes of that variant
+pub struct RuntimeKeychain {
+    kem: Box<dyn Kem>,
+    pub public_key: Option<Vec<u8>>,
+    pub secret_key: Option<Zeroizing<Vec<u8>>>,
+    pub shared_secret: Option<Zeroizing<Vec<u8>>>,
+    pub ciphertext: Option<Vec<u8>>,
+}
+
+impl RuntimeKeychain {
+    pub fn new(variant: Variant) -> Result<Self, CryptError> {
+        let kem = variant.kem();
+        let (public_key, secret_key) = kem.keypair();
+        let (shared_secret, ciphertext) = kem.encapsulate(&public_key)?;
+        Ok(Self {
+            kem,
+            public_key: Some(public_key),
+            secret_key: Some(secret_key),
+            shared_secret: Some(shared_secret),
+            ciphertext: Some(ciphertext),
+        })
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.kem.variant()
+    }
+
+    pub fn encapsulate(&mut self) -> Result<(), CryptError> {
+        let public_key = self.public_key.as_ref().ok_or(CryptError::MissingPublicKey)?;
+        let (shared_secret, ciphertext) = self.kem.encapsulate(public_key)?;
+        self.shared_secret = Some(shared_secret);
+        self.ciphertext = Some(ciphertext);
+        Ok(())
+    }
+
+    pub fn decapsulate(&self) -> Result<Zeroizing<Vec<u8>>, CryptError> {
+        let ciphertext = self.ciphertext.as_ref().ok_or(CryptError::MissingCiphertext)?;
+        let secret_key = self.secret_key.as_ref().ok_or(CryptError::MissingSecretKey)?;
+        self.kem.decapsulate(ciphertext, secret_key)
+    }
+}
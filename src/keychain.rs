@@ -3,76 +3,317 @@ use pqcrypto_traits::kem::{Ciphertext, PublicKey, SecretKey, SharedSecret};
 use aes::cipher::{BlockCipher, BlockEncrypt, BlockDecrypt, KeyInit, generic_array::GenericArray};
 use sha2::Sha256;
 use hmac::{Hmac, Mac};
-use std::{error::Error, ffi::OsStr, fmt, fs, path::Path, path::PathBuf, result::Result, env};
+use std::{ffi::OsStr, fs, future::Future, io, path::Path, path::PathBuf, result::Result, env, sync::atomic::{AtomicU64, Ordering}, time::Duration};
 use tokio::runtime;
 use crate::{KeychainMceliece as Keychain, FileMceliece as File};
 use crypt_guard_sign::{self, *};
 use pqcrypto_falcon::falcon1024;
+use subtle::ConstantTimeEq;
+use hkdf::Hkdf;
+use spki::{der::{asn1::BitStringRef, Decode, Encode}, AlgorithmIdentifierRef, ObjectIdentifier, SubjectPublicKeyInfoRef};
+use sha2::Sha512;
+use zeroize::Zeroizing;
+use crate::envelope;
+pub use crate::error::CryptError;
+
+pub const PUBLIC_KEY_LEN: usize = mceliece8192128::public_key_bytes();
+pub const SECRET_KEY_LEN: usize = mceliece8192128::secret_key_bytes();
+pub const CIPHERTEXT_LEN: usize = mceliece8192128::ciphertext_bytes();
+pub const SHARED_SECRET_LEN: usize = mceliece8192128::shared_secret_bytes();
+
+// No OID is registered for Classic McEliece yet, this is mceliece8192128 under the Bouncy Castle PQC KEM arc
+pub const MCELIECE_8192128_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.22554.5.1.9");
+
+pub(crate) fn check_distinct_keys(key: &[u8], hmac_key: &[u8]) -> Result<(), CryptError> {
+    if bool::from(key.ct_eq(hmac_key)) {
+        Err(CryptError::IdenticalKeys)
+    } else {
+        Ok(())
+    }
+}
+
+const METADATA_FILE_MODE: u32 = 0o644;
+
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Key files are written next to their final path first and renamed into place, so an interrupted save never leaves a truncated key behind.
+// The process id and a counter keep concurrent saves of the same file from sharing a temporary file
+pub(crate) fn temp_path_for(path: &str) -> String {
+    format!("{}.{}.{}.tmp", path, std::process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+fn write_atomically(path: &str, contents: &[u8], mode: u32) -> Result<(), CryptError> {
+    let temp_path = temp_path_for(path);
+    let result = with_retry(IO_RETRY_ATTEMPTS, || {
+        let mut file = create_with_mode(&temp_path, mode)?;
+        std::io::Write::write_all(&mut file, contents)?;
+        file.sync_all()
+    })
+    .and_then(|()| with_retry(IO_RETRY_ATTEMPTS, || fs::rename(&temp_path, path)));
+    // A failed save leaves nothing behind, whichever step it failed in
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    Ok(result?)
+}
+
+// The mode is set before any contents are written, explicitly as well since open applies the umask and
+// leaves the mode of an existing file alone. Other platforms keep their default permissions
+#[cfg(unix)]
+fn create_with_mode(path: &str, mode: u32) -> io::Result<fs::File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_with_mode(path: &str, _mode: u32) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
+
+#[cfg(unix)]
+async fn write_with_mode_async(path: &str, contents: &[u8], mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut file = tokio::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path).await?;
+    file.set_permissions(fs::Permissions::from_mode(mode)).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, contents).await?;
+    file.sync_all().await
+}
+
+#[cfg(not(unix))]
+async fn write_with_mode_async(path: &str, contents: &[u8], _mode: u32) -> io::Result<()> {
+    tokio::fs::write(path, contents).await
+}
+
+async fn write_atomically_async(path: &str, contents: &[u8], mode: u32) -> Result<(), CryptError> {
+    let temp_path = temp_path_for(path);
+    let mut result = with_retry_async(IO_RETRY_ATTEMPTS, || write_with_mode_async(&temp_path, contents, mode)).await;
+    if result.is_ok() {
+        result = with_retry_async(IO_RETRY_ATTEMPTS, || tokio::fs::rename(&temp_path, path)).await;
+    }
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    Ok(result?)
+}
+
+// Saves and loads on network filesystems can fail spuriously, so transient errors are retried with a doubling delay
+pub(crate) const IO_RETRY_ATTEMPTS: u32 = 3;
+const IO_RETRY_BASE_DELAY: Duration = Duration::from_millis(20);
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+pub(crate) fn with_retry<T>(attempts: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = IO_RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(err) if is_transient(&err) && attempt < attempts => {
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub(crate) async fn with_retry_async<T, F, Fut>(attempts: u32, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut delay = IO_RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(err) if is_transient(&err) && attempt < attempts => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Files are hashed in fixed size chunks, so signing or verifying a file's digest takes constant memory
+const DIGEST_CHUNK_SIZE: usize = 64 * 1024;
+
+pub(crate) fn file_digest(path: &Path) -> Result<[u8; 64], CryptError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = <Sha512 as sha2::Digest>::new();
+    let mut buffer = vec![0u8; DIGEST_CHUNK_SIZE];
+    loop {
+        match io::Read::read(&mut file, &mut buffer) {
+            Ok(0) => break,
+            Ok(read) => sha2::Digest::update(&mut hasher, &buffer[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(sha2::Digest::finalize(hasher).into())
+}
+
+// All randomness for key generation and encapsulation goes through these two functions. pqcrypto fills it
+// from the OS (getrandom) inside the C implementation, so a caller supplied or seeded RNG cannot be injected
+pub(crate) fn generate_keypair() -> (mceliece8192128::PublicKey, mceliece8192128::SecretKey) {
+    mceliece8192128::keypair()
+}
+
+pub(crate) fn generate_encapsulation(public_key: &mceliece8192128::PublicKey) -> (mceliece8192128::SharedSecret, mceliece8192128::Ciphertext) {
+    mceliece8192128::encapsulate(public_key)
+}
+
+pub struct DerivedKeys {
+    pub aes_key: [u8; 32],
+    pub hmac_key: [u8; 64],
+}
+
+impl DerivedKeys {
+    pub fn from_shared_secret(shared_secret: &dyn SharedSecret) -> Self {
+        Self::from_secret_bytes(shared_secret.as_bytes())
+    }
+
+    // Every McEliece variant has a 32 byte shared secret, so RuntimeKeychain secrets derive keys the same way
+    pub(crate) fn from_secret_bytes(shared_secret: &[u8]) -> Self {
+        let hkdf = Hkdf::<Sha512>::new(None, shared_secret);
+        let mut aes_key = [0u8; 32];
+        let mut hmac_key = [0u8; 64];
+        hkdf.expand(b"CryptGuardMceliece AES-256 key", &mut aes_key)
+            .expect("32 bytes is a valid HKDF-SHA512 output length");
+        hkdf.expand(b"CryptGuardMceliece HMAC-SHA512 key", &mut hmac_key)
+            .expect("64 bytes is a valid HKDF-SHA512 output length");
+        Self { aes_key, hmac_key }
+    }
+
+    // The AES key is as long as a shared secret, so it can be passed wherever a shared secret is used as the AES key
+    pub(crate) fn aes_key_as_shared_secret(&self) -> mceliece8192128::SharedSecret {
+        SharedSecret::from_bytes(&self.aes_key).expect("the AES key has the length of a shared secret")
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KeychainPaths {
+    pub public_key: Option<PathBuf>,
+    pub secret_key: Option<PathBuf>,
+    pub shared_secret: Option<PathBuf>,
+    pub ciphertext: Option<PathBuf>,
+}
 
 #[derive(Debug)]
-pub enum CryptError {
-    IOError,
-    MessageExtractionError,
-    InvalidMessageFormat,
-    HexError(hex::FromHexError),
-    EncapsulationError,
-    DecapsulationError,
-    WriteError,
-    HmacVerificationError,
-    HmacShortData,
-    HmacKeyErr,
-    HexDecodingError(String),
-    UniqueFilenameFailed,
-    MissingSecretKey,
-    MissingPublicKey,
-    MissingCiphertext,
-    MissingSharedSecret,
-    MissingData,
-    InvalidParameters,
-    PathError,
-    Utf8Error,
-    SigningFailed,
-    SignatureVerificationFailed,
-    InvalidSignatureLength,
-    InvalidSignature,
-}
-
-impl fmt::Display for CryptError {
-   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-       match self {
-           CryptError::IOError => write!(f, "IO error occurred"),
-           CryptError::MessageExtractionError => write!(f, "Error extracting message"),
-           CryptError::InvalidMessageFormat => write!(f, "Invalid message format"),
-           CryptError::HexError(err) => write!(f, "Hex error: {}", err),
-           CryptError::EncapsulationError => write!(f, "Encapsulation error"),
-           CryptError::DecapsulationError => write!(f, "Decapsulation error"),
-           CryptError::WriteError => write!(f, "Write error"),
-           CryptError::HmacVerificationError => write!(f, "HMAC verification error"),
-           CryptError::HmacShortData => write!(f, "Data is too short for HMAC verification"),
-           CryptError::HmacKeyErr => write!(f, "HMAC can take key of any size"),
-           CryptError::HexDecodingError(err) => write!(f, "Hex decoding error: {}", err),
-           CryptError::UniqueFilenameFailed => write!(f, "Unique filename failed"),
-           CryptError::MissingSecretKey => write!(f, "Missing secret key"),
-           CryptError::MissingPublicKey => write!(f, "Missing public key"),
-           CryptError::MissingCiphertext => write!(f, "Missing ciphertext"),
-           CryptError::MissingSharedSecret => write!(f, "Missing shared secret"),
-           CryptError::MissingData => write!(f, "Missing data"),
-           CryptError::InvalidParameters => write!(f, "You provided Invalid parameters"),
-           CryptError::PathError => write!(f, "The provided path does not exist!"),
-           CryptError::Utf8Error => write!(f, "UTF-8 conversion error"),
-           CryptError::SigningFailed => write!(f, "Signing file using falcon 1024 failed!"),
-           CryptError::SignatureVerificationFailed => write!(f, "verification of signature using falcon 1024 failed!"),
-           CryptError::InvalidSignature => write!(f, "Signature not valid!"),
-           CryptError::InvalidSignatureLength => write!(f, "Data is too short for HMAC verification"),
-       }
-   }
-}
-
-impl Error for CryptError {}
-
-impl From<hex::FromHexError> for CryptError {
-    fn from(error: hex::FromHexError) -> Self {
-        CryptError::HexError(error)
+pub struct KeychainSummary {
+    pub title: String,
+    pub generations: u64,
+    pub has_public_key: bool,
+    pub has_secret_key: bool,
+    pub has_shared_secret: bool,
+    pub has_ciphertext: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentChange {
+    Unchanged,
+    Changed,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeychainDiff {
+    pub public_key: ComponentChange,
+    pub secret_key: ComponentChange,
+    pub shared_secret: ComponentChange,
+    pub ciphertext: ComponentChange,
+}
+
+impl KeychainDiff {
+    pub fn is_unchanged(&self) -> bool {
+        [self.public_key, self.secret_key, self.shared_secret, self.ciphertext]
+            .iter()
+            .all(|change| *change == ComponentChange::Unchanged)
+    }
+}
+
+// Bytes are compared in constant time so diffing secret components reveals only whether they differ
+fn component_change(before: Option<&[u8]>, after: Option<&[u8]>) -> ComponentChange {
+    match (before, after) {
+        (None, None) => ComponentChange::Unchanged,
+        (None, Some(_)) => ComponentChange::Added,
+        (Some(_), None) => ComponentChange::Removed,
+        (Some(before), Some(after)) if bool::from(before.ct_eq(after)) => ComponentChange::Unchanged,
+        (Some(_), Some(_)) => ComponentChange::Changed,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KeyUsage {
+    Encrypt,
+    Sign,
+    Both,
+}
+
+impl KeyUsage {
+    fn name(self) -> &'static str {
+        match self {
+            KeyUsage::Encrypt => "encrypt",
+            KeyUsage::Sign => "sign",
+            KeyUsage::Both => "both",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "encrypt" => Some(KeyUsage::Encrypt),
+            "sign" => Some(KeyUsage::Sign),
+            "both" => Some(KeyUsage::Both),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ComponentFlags(u8);
+
+impl ComponentFlags {
+    pub const PUBLIC_KEY: Self = Self(1);
+    pub const SECRET_KEY: Self = Self(1 << 1);
+    pub const SHARED_SECRET: Self = Self(1 << 2);
+    pub const CIPHERTEXT: Self = Self(1 << 3);
+    pub const ALL: Self = Self(0b1111);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl core::ops::BitOr for ComponentFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl core::ops::BitOrAssign for ComponentFlags {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
     }
 }
 
@@ -84,47 +325,221 @@ pub enum KeyTypes {
     Ciphertext,
 }
 
-impl File {
-    pub async fn load(path: PathBuf, file_type: KeyTypes) -> Result<Vec<u8>, CryptError> {
-        let file_content = fs::read_to_string(&path).map_err(|_| CryptError::IOError)?;
-        let (start_label, end_label) = match file_type {
+pub(crate) fn expected_len(file_type: &KeyTypes) -> usize {
+    match file_type {
+        KeyTypes::PublicKey => mceliece8192128::public_key_bytes(),
+        KeyTypes::SecretKey => mceliece8192128::secret_key_bytes(),
+        KeyTypes::SharedSecret => mceliece8192128::shared_secret_bytes(),
+        KeyTypes::Ciphertext => mceliece8192128::ciphertext_bytes(),
+        KeyTypes::All => unreachable!(),
+    }
+}
+
+pub(crate) fn check_key_len(file_type: &KeyTypes, bytes: &[u8]) -> Result<(), CryptError> {
+    let expected = expected_len(file_type);
+    if bytes.len() != expected {
+        return Err(CryptError::InvalidKeyLength { expected, actual: bytes.len() });
+    }
+    Ok(())
+}
+
+fn from_slice<T>(file_type: KeyTypes, bytes: &[u8], from_bytes: fn(&[u8]) -> Result<T, pqcrypto_traits::Error>) -> Result<T, CryptError> {
+    check_key_len(&file_type, bytes)?;
+    from_bytes(bytes).map_err(|_| CryptError::InvalidKeyLength { expected: expected_len(&file_type), actual: bytes.len() })
+}
+
+fn public_key_fingerprint(public_key: &mceliece8192128::PublicKey) -> [u8; 32] {
+    <Sha256 as sha2::Digest>::digest(public_key.as_bytes()).into()
+}
+
+// A volatile write so the compiler can't drop the store to a value that is never read again
+fn overwrite_volatile<T>(slot: &mut T, value: T) {
+    unsafe { core::ptr::write_volatile(slot, value) };
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+pub fn public_key_from_slice(bytes: &[u8]) -> Result<mceliece8192128::PublicKey, CryptError> {
+    from_slice(KeyTypes::PublicKey, bytes, PublicKey::from_bytes)
+}
+
+pub fn secret_key_from_slice(bytes: &[u8]) -> Result<mceliece8192128::SecretKey, CryptError> {
+    from_slice(KeyTypes::SecretKey, bytes, SecretKey::from_bytes)
+}
+
+pub fn ciphertext_from_slice(bytes: &[u8]) -> Result<mceliece8192128::Ciphertext, CryptError> {
+    from_slice(KeyTypes::Ciphertext, bytes, Ciphertext::from_bytes)
+}
+
+pub fn shared_secret_from_slice(bytes: &[u8]) -> Result<mceliece8192128::SharedSecret, CryptError> {
+    from_slice(KeyTypes::SharedSecret, bytes, SharedSecret::from_bytes)
+}
+
+impl KeyTypes {
+    pub(crate) fn labels(&self) -> (&'static str, &'static str) {
+        match self {
             KeyTypes::PublicKey => ("-----BEGIN PUBLIC KEY-----\n", "\n-----END PUBLIC KEY-----"),
             KeyTypes::SecretKey => ("-----BEGIN SECRET KEY-----\n", "\n-----END SECRET KEY-----"),
             KeyTypes::SharedSecret => ("-----BEGIN SHARED SECRET-----\n", "\n-----END SHARED SECRET-----"),
             KeyTypes::Ciphertext => ("-----BEGIN CIPHERTEXT-----\n", "\n-----END CIPHERTEXT-----"),
             KeyTypes::All => unreachable!(),
-        };
+        }
+    }
 
-        let start = file_content.find(start_label)
-            .ok_or(CryptError::IOError)?;
-        let end = file_content.rfind(end_label)
+    // Secret components are owner only, the public ones readable by everyone
+    pub(crate) fn file_mode(&self) -> u32 {
+        match self {
+            KeyTypes::SecretKey | KeyTypes::SharedSecret => 0o600,
+            KeyTypes::PublicKey | KeyTypes::Ciphertext => 0o644,
+            KeyTypes::All => unreachable!(),
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            KeyTypes::PublicKey => "pub",
+            KeyTypes::SecretKey => "sec",
+            KeyTypes::SharedSecret => "ss",
+            KeyTypes::Ciphertext => "ct",
+            KeyTypes::All => unreachable!(),
+        }
+    }
+}
+
+impl File {
+    pub async fn load(path: PathBuf, file_type: KeyTypes) -> Result<Vec<u8>, CryptError> {
+        let file_content = with_retry_async(IO_RETRY_ATTEMPTS, || tokio::fs::read_to_string(&path)).await?;
+        Self::parse(&file_content, file_type)
+    }
+
+    // Blocking counterpart of load for callers outside an async runtime
+    pub fn load_blocking(path: PathBuf, file_type: KeyTypes) -> Result<Vec<u8>, CryptError> {
+        let file_content = with_retry(IO_RETRY_ATTEMPTS, || fs::read_to_string(&path))?;
+        Self::parse(&file_content, file_type)
+    }
+
+    fn parse(file_content: &str, file_type: KeyTypes) -> Result<Vec<u8>, CryptError> {
+        // The labels are matched without their line breaks, so files edited on Windows (\r\n) or with
+        // trailing whitespace load without first building a normalized copy of the whole file
+        let (start_label, end_label) = file_type.labels();
+        let (start_label, end_label) = (start_label.trim(), end_label.trim());
+
+        let start = match file_content.find(start_label) {
+            Some(start) => start,
+            None if Self::contains_key_label(file_content) => return Err(CryptError::WrongKeyType),
+            None => return Err(CryptError::IOError),
+        };
+        let body = &file_content[start + start_label.len()..];
+        let end = body.rfind(end_label)
             .ok_or(CryptError::IOError)?;
 
-        let content = &file_content[start + start_label.len()..end];
-        hex::decode(content).map_err(CryptError::HexError)
+        let bytes = Self::decode_hex(body[..end].trim())?;
+        check_key_len(&file_type, &bytes)?;
+        Ok(bytes)
+    }
+
+    // Decodes in a single pass into a buffer allocated once at its final size
+    fn decode_hex(content: &str) -> Result<Vec<u8>, CryptError> {
+        if !content.len().is_multiple_of(2) {
+            return Err(CryptError::HexError(hex::FromHexError::OddLength));
+        }
+        let mut bytes = vec![0u8; content.len() / 2];
+        hex::decode_to_slice(content, &mut bytes).map_err(CryptError::HexError)?;
+        Ok(bytes)
+    }
+
+    fn contains_key_label(file_content: &str) -> bool {
+        [
+            "-----BEGIN PUBLIC KEY-----",
+            "-----BEGIN SECRET KEY-----",
+            "-----BEGIN SHARED SECRET-----",
+            "-----BEGIN CIPHERTEXT-----",
+        ].iter().any(|label| file_content.contains(label))
+    }
+}
+
+// A content key wrapped for one recipient: the KEM ciphertext, the key masked with the derived AES key
+// and an HMAC over both under the derived HMAC key
+pub const WRAPPED_KEY_LEN: usize = CIPHERTEXT_LEN + 32 + 64;
+
+pub(crate) fn wrap_content_key(public_key: &mceliece8192128::PublicKey, content_key: &[u8; 32]) -> Vec<u8> {
+    let (shared_secret, ciphertext) = generate_encapsulation(public_key);
+    let keys = DerivedKeys::from_shared_secret(&shared_secret);
+
+    let mut wrapped = Vec::with_capacity(WRAPPED_KEY_LEN);
+    wrapped.extend_from_slice(ciphertext.as_bytes());
+    wrapped.extend(content_key.iter().zip(keys.aes_key.iter()).map(|(key, mask)| key ^ mask));
+    let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(&keys.hmac_key).expect("HMAC can take key of any size");
+    mac.update(&wrapped);
+    wrapped.extend_from_slice(&mac.finalize().into_bytes());
+    wrapped
+}
+
+// A wrong secret key decapsulates to an unrelated shared secret, which the HMAC check then rejects
+pub(crate) fn unwrap_content_key(secret_key: &mceliece8192128::SecretKey, wrapped: &[u8]) -> Result<Zeroizing<[u8; 32]>, CryptError> {
+    if wrapped.len() != WRAPPED_KEY_LEN {
+        return Err(CryptError::InvalidKeyLength { expected: WRAPPED_KEY_LEN, actual: wrapped.len() });
+    }
+
+    let (authenticated, tag) = wrapped.split_at(WRAPPED_KEY_LEN - 64);
+    let (ciphertext, masked) = authenticated.split_at(mceliece8192128::ciphertext_bytes());
+    let shared_secret = decapsulate(&ciphertext_from_slice(ciphertext)?, secret_key);
+    let keys = DerivedKeys::from_shared_secret(&shared_secret);
+    envelope::check_hmac(&keys.hmac_key, authenticated, tag)?;
+
+    let mut content_key = Zeroizing::new([0u8; 32]);
+    for ((key, masked), mask) in content_key.iter_mut().zip(masked).zip(keys.aes_key.iter()) {
+        *key = masked ^ mask;
+    }
+    Ok(content_key)
+}
+
+// An empty keychain to fill with the load_* methods, unlike new it generates nothing
+impl Default for Keychain {
+    fn default() -> Self {
+        Self {
+            public_key: None,
+            secret_key: None,
+            shared_secret: None,
+            ciphertext: None,
+            usage: KeyUsage::Both,
+        }
     }
 }
 
 impl Keychain {
     pub fn new() -> Result<Self, CryptError> {
-        let (pk, sk) = keypair();
-        let (ss, ct) = encapsulate(&pk);
+        let (pk, sk) = generate_keypair();
+        let (ss, ct) = generate_encapsulation(&pk);
         Ok(Self {
             public_key: Some(pk),
             secret_key: Some(sk),
             shared_secret: Some(ss),
             ciphertext: Some(ct),
+            usage: KeyUsage::Both,
         })
     }
     
-    pub fn new_keys(path: &str, name: &str) -> Result<Self, CryptError> {
-        let (pk, sk) = keypair();
-        let keys = Self {
+    pub fn new_keypair_only() -> Result<Self, CryptError> {
+        let (pk, sk) = generate_keypair();
+        Ok(Self {
             public_key: Some(pk),
             secret_key: Some(sk),
             shared_secret: None,
             ciphertext: None,
-        };
+            usage: KeyUsage::Both,
+        })
+    }
+
+    // Generates a keypair and saves it from within the caller's runtime
+    pub async fn generate_and_save(base_path: &str, name: &str) -> Result<(Self, Vec<PathBuf>), CryptError> {
+        let keys = Self::new_keypair_only()?;
+        let paths = keys.save_keys(base_path, name).await?;
+        Ok((keys, paths))
+    }
+
+    #[deprecated(note = "spawns a Tokio runtime per call and drops save errors, use generate_and_save instead")]
+    pub fn new_keys(path: &str, name: &str) -> Result<Self, CryptError> {
+        let keys = Self::new_keypair_only()?;
         let rt = runtime::Runtime::new().unwrap();
         rt.block_on(async {
             keys.save_keys(path, name).await;
@@ -133,22 +548,21 @@ impl Keychain {
     }
     
     pub fn find_highest_numbered_file(dir_path: &Path, base_filename: &str, extension: &str) -> Option<PathBuf> {
-        let mut highest_numbered_file: Option<(i32, PathBuf)> = None;
-
-        if dir_path.is_dir() {
-            for entry in fs::read_dir(dir_path).unwrap() {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if path.is_file() && path.extension() == Some(OsStr::new(extension)) {
-                        if let Some(stem) = path.file_stem().and_then(OsStr::to_str) {
-                            if stem.starts_with(base_filename) {
-                                let number_part = &stem[base_filename.len()..];
-                                if let Ok(number) = number_part.parse::<i32>() {
-                                    if highest_numbered_file.is_none() || highest_numbered_file.as_ref().unwrap().0 < number {
-                                        highest_numbered_file = Some((number, path));
-                                    }
-                                }
-                            }
+        let mut highest_numbered_file: Option<(u64, PathBuf)> = None;
+
+        // A missing, non-directory or unreadable path simply has no numbered files
+        let entries = match fs::read_dir(dir_path) {
+            Ok(entries) => entries,
+            Err(_) => return None,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_file() && path.extension() == Some(OsStr::new(extension)) {
+                if let Some(stem) = path.file_stem().and_then(OsStr::to_str) {
+                    if let Some(number) = Self::generation_number(stem, base_filename) {
+                        if highest_numbered_file.is_none() || highest_numbered_file.as_ref().unwrap().0 < number {
+                            highest_numbered_file = Some((number, path));
                         }
                     }
                 }
@@ -158,213 +572,484 @@ impl Keychain {
         highest_numbered_file.map(|(_, path)| path)
     }
 
-    pub fn show(&self) -> Result<(), CryptError> {
-        if let (Some(ref pk), Some(ref sk), Some(ref ss), Some(ref ct)) = (self.public_key.as_ref(), self.secret_key.as_ref(), self.shared_secret.as_ref(), self.ciphertext.as_ref()) {
-            let ss2 = decapsulate(ct, sk);
-            println!("Public Key: {}\n\nSecret Key: {}\n\nShared secret: {}\n\nDecapsulated shared secret: {}", hex::encode(pk.as_bytes()), hex::encode(sk.as_bytes()), hex::encode(ss.as_bytes()), hex::encode(ss2.as_bytes()));
-            Ok(())
-        } else {
-            Err(CryptError::DecapsulationError)
+    // generate_unique_filename names generation 0 <base>.<ext> and later ones <base>_<n>.<ext>
+    fn generation_number(stem: &str, base_filename: &str) -> Option<u64> {
+        stem.strip_prefix(base_filename).and_then(|number_part| match number_part {
+            "" => Some(0),
+            _ => number_part.strip_prefix('_').unwrap_or(number_part).parse::<u64>().ok(),
+        })
+    }
+
+    // Saving unchanged content again hands back the latest generation, but only when it holds every component
+    // being saved, so either the whole set is reused or all of it is written anew. Files are compared by digest
+    fn find_saved_generation(dir_path: &Path, base_filename: &str, armored: &[(&KeyTypes, String)]) -> Option<Vec<PathBuf>> {
+        armored.iter()
+            .map(|(component, contents)| {
+                let path = Self::find_highest_numbered_file(dir_path, base_filename, component.extension())?;
+                let expected: [u8; 64] = <Sha512 as sha2::Digest>::digest(contents.as_bytes()).into();
+                (file_digest(&path).ok()? == expected).then_some(path)
+            })
+            .collect()
+    }
+
+    // The first generation is saved as <title>.<ext>, later ones as <title>_<n>.<ext>
+    pub fn latest_paths(base_path: &str, title: &str) -> Result<KeychainPaths, CryptError> {
+        let dir = Path::new(base_path).join(title);
+        if !dir.is_dir() {
+            return Err(CryptError::PathError);
         }
+
+        let latest = |extension: &str| {
+            Self::find_highest_numbered_file(&dir, title, extension)
+        };
+
+        Ok(KeychainPaths {
+            public_key: latest("pub"),
+            secret_key: latest("sec"),
+            shared_secret: latest("ss"),
+            ciphertext: latest("ct"),
+        })
     }
 
-    pub async fn save(&self, base_path: &str, title: &str) -> Result<(), CryptError> {
-        let dir_path = format!("{}/{}", base_path, title);
-        let dir = std::path::Path::new(&dir_path);
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir).map_err(|_| CryptError::WriteError)?;
-        }
-
-        let public_key_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "pub");
-        let secret_key_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "sec");
-        let shared_secret_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "ss");
-        let ciphertext_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "ct");
-
-        fs::write(
-            &public_key_path, 
-            format!(
-                "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----",
-                hex::encode(self.public_key.as_ref().expect("Public key is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
-
-        fs::write(
-            &secret_key_path, 
-            format!(
-                "-----BEGIN SECRET KEY-----\n{}\n-----END SECRET KEY-----",
-                hex::encode(self.secret_key.as_ref().expect("Secret key is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
-
-        fs::write(
-            &shared_secret_path, 
-            format!(
-                "-----BEGIN SHARED SECRET-----\n{}\n-----END SHARED SECRET-----",
-                hex::encode(self.shared_secret.as_ref().expect("Shared secret is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
-
-        fs::write(
-            &ciphertext_path, 
-            format!(
-                "-----BEGIN CIPHERTEXT-----\n{}\n-----END CIPHERTEXT-----",
-                hex::encode(self.ciphertext.as_ref().expect("Ciphertext is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
+    // Every subdirectory holding at least one key file of its own title counts as a keychain
+    pub fn list(base_path: &Path) -> Result<Vec<KeychainSummary>, CryptError> {
+        let mut summaries = Vec::new();
+        for entry in fs::read_dir(base_path)?.flatten() {
+            if !entry.path().is_dir() {
+                continue;
+            }
+            let title = match entry.file_name().to_str() {
+                Some(title) => title.to_string(),
+                None => continue,
+            };
 
-        Ok(())
+            let paths = Self::latest_paths(base_path.to_str().ok_or(CryptError::PathError)?, &title)?;
+            let generations = [&paths.public_key, &paths.secret_key, &paths.shared_secret, &paths.ciphertext]
+                .iter()
+                .filter_map(|path| path.as_ref())
+                .filter_map(|path| path.file_stem().and_then(OsStr::to_str))
+                .map(|stem| match stem.strip_prefix(&format!("{}_", title)) {
+                    Some(number) => number.parse::<u64>().map_or(1, |number| number + 1),
+                    None => 1,
+                })
+                .max();
+
+            if let Some(generations) = generations {
+                summaries.push(KeychainSummary {
+                    title,
+                    generations,
+                    has_public_key: paths.public_key.is_some(),
+                    has_secret_key: paths.secret_key.is_some(),
+                    has_shared_secret: paths.shared_secret.is_some(),
+                    has_ciphertext: paths.ciphertext.is_some(),
+                });
+            }
+        }
+
+        summaries.sort_by(|a, b| a.title.cmp(&b.title));
+        Ok(summaries)
     }
 
-    pub async fn save_keys(&self, base_path: &str, title: &str) -> Result<(), CryptError> {
-        let dir_path = format!("{}/{}", base_path, title);
-        let dir = std::path::Path::new(&dir_path);
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir).map_err(|_| CryptError::WriteError)?;
-        }
-
-        let public_key_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "pub");
-        let secret_key_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "sec");
-
-        fs::write(
-            &public_key_path, 
-            format!(
-                "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----",
-                hex::encode(self.public_key.as_ref().expect("Public key is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
-
-        fs::write(
-            &secret_key_path, 
-            format!(
-                "-----BEGIN SECRET KEY-----\n{}\n-----END SECRET KEY-----",
-                hex::encode(self.secret_key.as_ref().expect("Secret key is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
+    pub async fn load_latest(base_path: &str, title: &str) -> Result<Self, CryptError> {
+        let paths = Self::latest_paths(base_path, title)?;
+        let mut keychain = Self {
+            public_key: None,
+            secret_key: None,
+            shared_secret: None,
+            ciphertext: None,
+            usage: Self::load_usage(&format!("{}/{}", base_path, title), title).await?,
+        };
 
+        if let Some(path) = paths.public_key {
+            keychain.public_key = Some(Self::public_key_from_file(path).await?);
+        }
+        if let Some(path) = paths.secret_key {
+            keychain.secret_key = Some(Self::secret_key_from_file(path).await?);
+        }
+        if let Some(path) = paths.shared_secret {
+            keychain.shared_secret = Some(Self::shared_secret_from_file(path).await?);
+        }
+        if let Some(path) = paths.ciphertext {
+            keychain.ciphertext = Some(Self::ciphertext_from_file(path).await?);
+        }
+        Ok(keychain)
+    }
+
+    pub fn encapsulate(&mut self) -> Result<(), CryptError> {
+        let public_key = self.public_key.as_ref().ok_or(CryptError::MissingPublicKey)?;
+        let (shared_secret, ciphertext) = generate_encapsulation(public_key);
+        self.shared_secret = Some(shared_secret);
+        self.ciphertext = Some(ciphertext);
         Ok(())
     }
 
-    pub async fn save_public_key(&self, base_path: &str, title: &str) -> Result<(), CryptError> {
-        let dir_path = format!("{}/{}", base_path, title);
-        let dir = std::path::Path::new(&dir_path);
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir).map_err(|_| CryptError::WriteError)?;
+    // A malformed ciphertext still yields a secret (implicit rejection), pqcrypto gives no way to tell the two apart
+    pub fn decapsulate(&self) -> Result<mceliece8192128::SharedSecret, CryptError> {
+        let ciphertext = self.ciphertext.as_ref().ok_or(CryptError::MissingCiphertext)?;
+        let secret_key = self.secret_key.as_ref().ok_or(CryptError::MissingSecretKey)?;
+        Ok(decapsulate(ciphertext, secret_key))
+    }
+
+    // Zeroes the secret key and shared secret in place before dropping them, the public parts are kept.
+    // The pqcrypto types are Copy, so copies handed out earlier are not affected
+    pub fn wipe(&mut self) {
+        if let Some(secret_key) = self.secret_key.as_mut() {
+            overwrite_volatile(secret_key, SecretKey::from_bytes(&[0u8; SECRET_KEY_LEN]).unwrap());
         }
+        if let Some(shared_secret) = self.shared_secret.as_mut() {
+            overwrite_volatile(shared_secret, SharedSecret::from_bytes(&[0u8; SHARED_SECRET_LEN]).unwrap());
+        }
+        self.secret_key = None;
+        self.shared_secret = None;
+    }
 
-        let public_key_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "pub");
+    // The public key is recomputable from the Goppa polynomial and support inside the secret key, but
+    // pqcrypto exposes neither that step nor a seeded key generation, so this fails until it does
+    pub fn recover_public_from_secret(&mut self) -> Result<(), CryptError> {
+        self.secret_key.as_ref().ok_or(CryptError::MissingSecretKey)?;
+        Err(CryptError::NotImplemented("deriving the public key from the secret key"))
+    }
+
+    // Reports how other differs from self, e.g. a snapshot taken before rotate_keys against the rotated keychain
+    pub fn diff(&self, other: &Keychain) -> KeychainDiff {
+        KeychainDiff {
+            public_key: component_change(self.public_key.as_ref().map(|key| key.as_bytes()), other.public_key.as_ref().map(|key| key.as_bytes())),
+            secret_key: component_change(self.secret_key.as_ref().map(|key| key.as_bytes()), other.secret_key.as_ref().map(|key| key.as_bytes())),
+            shared_secret: component_change(self.shared_secret.as_ref().map(|key| key.as_bytes()), other.shared_secret.as_ref().map(|key| key.as_bytes())),
+            ciphertext: component_change(self.ciphertext.as_ref().map(|key| key.as_bytes()), other.ciphertext.as_ref().map(|key| key.as_bytes())),
+        }
+    }
 
-        fs::write(
-            &public_key_path, 
-            format!(
-                "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----",
-                hex::encode(self.public_key.as_ref().expect("Public key is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
+    // Fills the missing components from other, anything already present in self is kept
+    pub fn merge(&mut self, other: &Keychain) {
+        self.public_key = self.public_key.or(other.public_key);
+        self.secret_key = self.secret_key.or(other.secret_key);
+        self.shared_secret = self.shared_secret.or(other.shared_secret);
+        self.ciphertext = self.ciphertext.or(other.ciphertext);
+    }
 
+    // Stores the result of decapsulate, e.g. after load_ciphertext and load_secret_key
+    pub fn fill_shared_secret(&mut self) -> Result<(), CryptError> {
+        self.shared_secret = Some(self.decapsulate()?);
         Ok(())
     }
 
-      
-    pub async fn save_secret_key(&self, base_path: &str, title: &str) -> Result<(), CryptError> {
-        let dir_path = format!("{}/{}", base_path, title);
-        let dir = std::path::Path::new(&dir_path);
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir).map_err(|_| CryptError::WriteError)?;
+    // Mismatched keys still decapsulate, but to an unrelated shared secret
+    pub fn verify_keypair(&self) -> Result<bool, CryptError> {
+        let public_key = self.public_key.as_ref().ok_or(CryptError::MissingPublicKey)?;
+        let secret_key = self.secret_key.as_ref().ok_or(CryptError::MissingSecretKey)?;
+        let (shared_secret, ciphertext) = generate_encapsulation(public_key);
+        let decapsulated = decapsulate(&ciphertext, secret_key);
+        Ok(bool::from(shared_secret.as_bytes().ct_eq(decapsulated.as_bytes())))
+    }
+
+    // For pinning checks against an expected public key, compared in constant time
+    pub fn equals_public(&self, other: &mceliece8192128::PublicKey) -> Result<bool, CryptError> {
+        let public_key = self.public_key.as_ref().ok_or(CryptError::MissingPublicKey)?;
+        Ok(bool::from(public_key.as_bytes().ct_eq(other.as_bytes())))
+    }
+
+    // A copy that carries no secret material and is safe to hand out
+    pub fn public_only(&self) -> Keychain {
+        Self {
+            public_key: self.public_key,
+            secret_key: None,
+            shared_secret: None,
+            ciphertext: None,
+            usage: self.usage,
+        }
+    }
+
+    pub fn check_usage(&self, required: KeyUsage) -> Result<(), CryptError> {
+        if self.usage == KeyUsage::Both || self.usage == required {
+            Ok(())
+        } else {
+            Err(CryptError::UsageNotPermitted)
         }
+    }
 
-        let secret_key_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "sec");
+    // Lowercase hex SHA-256 of the public key bytes
+    pub fn fingerprint(&self) -> Result<String, CryptError> {
+        let public_key = self.public_key.as_ref().ok_or(CryptError::MissingPublicKey)?;
+        Ok(hex::encode(public_key_fingerprint(public_key)))
+    }
+
+    pub fn present(&self) -> ComponentFlags {
+        let mut flags = ComponentFlags::empty();
+        if self.public_key.is_some() {
+            flags |= ComponentFlags::PUBLIC_KEY;
+        }
+        if self.secret_key.is_some() {
+            flags |= ComponentFlags::SECRET_KEY;
+        }
+        if self.shared_secret.is_some() {
+            flags |= ComponentFlags::SHARED_SECRET;
+        }
+        if self.ciphertext.is_some() {
+            flags |= ComponentFlags::CIPHERTEXT;
+        }
+        flags
+    }
 
-        fs::write(
-            &secret_key_path, 
-            format!(
-                "-----BEGIN SECRET KEY-----\n{}\n-----END SECRET KEY-----",
-                hex::encode(self.secret_key.as_ref().expect("Secret key is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
+    pub fn is_complete(&self) -> bool {
+        self.present() == ComponentFlags::ALL
+    }
 
+    pub fn show(&self) -> Result<(), CryptError> {
+        if !self.is_complete() {
+            return Err(CryptError::DecapsulationError);
+        }
+        let (ct, ss) = (self.ciphertext.as_ref().unwrap(), self.shared_secret.as_ref().unwrap());
+        let shared_secret_matches = bool::from(self.decapsulate()?.as_bytes().ct_eq(ss.as_bytes()));
+        // The secret key and shared secret never reach the subscriber, only whether they still agree
+        tracing::debug!(
+            fingerprint = %self.fingerprint()?,
+            ciphertext = %hex::encode(ct.as_bytes()),
+            shared_secret_matches,
+            "keychain contents"
+        );
         Ok(())
     }
 
+    pub async fn save(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        self.save_generation(base_path, title).await
+    }
+
+    async fn save_generation(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        self.save_components(base_path, title, &[KeyTypes::PublicKey, KeyTypes::SecretKey, KeyTypes::SharedSecret, KeyTypes::Ciphertext]).await
+    }
 
-    pub async fn save_ciphertext(&self, base_path: &str, title: &str) -> Result<(), CryptError> {
+    // Blocking counterpart of save for callers outside an async runtime
+    pub fn save_blocking(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
         let dir_path = format!("{}/{}", base_path, title);
-        let dir = std::path::Path::new(&dir_path);
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir).map_err(|_| CryptError::WriteError)?;
+        fs::create_dir_all(&dir_path)?;
+        write_atomically(&Self::metadata_path(&dir_path, title), self.metadata().as_bytes(), METADATA_FILE_MODE)?;
+
+        let components = [KeyTypes::PublicKey, KeyTypes::SecretKey, KeyTypes::SharedSecret, KeyTypes::Ciphertext];
+        let armored: Vec<_> = components.iter().map(|component| (component, self.armored(component))).collect();
+        if let Some(existing) = Self::find_saved_generation(Path::new(&dir_path), title, &armored) {
+            return Ok(existing);
+        }
+
+        let mut paths = Vec::new();
+        for (component, armored) in armored {
+            let path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), component.extension())?;
+            write_atomically(&path, armored.as_bytes(), component.file_mode())?;
+            paths.push(PathBuf::from(path));
         }
+        Ok(paths)
+    }
 
-        let ciphertext_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "ct");
+    async fn save_components(&self, base_path: &str, title: &str, components: &[KeyTypes]) -> Result<Vec<PathBuf>, CryptError> {
+        let dir_path = format!("{}/{}", base_path, title);
+        tokio::fs::create_dir_all(&dir_path).await?;
+        write_atomically_async(&Self::metadata_path(&dir_path, title), self.metadata().as_bytes(), METADATA_FILE_MODE).await?;
 
-        let ciphertext = self.ciphertext.as_ref().expect("Ciphertext is missing");
-        fs::write(
-            &ciphertext_path, 
-            format!(
-                "-----BEGIN CIPHERTEXT-----\n{}\n-----END CIPHERTEXT-----",
-                hex::encode(ciphertext.as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
+        let armored: Vec<_> = components.iter().map(|component| (component, self.armored(component))).collect();
+        if let Some(existing) = Self::find_saved_generation(Path::new(&dir_path), title, &armored) {
+            return Ok(existing);
+        }
 
-        Ok(())
+        let mut paths = Vec::new();
+        for (component, armored) in armored {
+            let path = Keychain::generate_unique_filename_async(&format!("{}/{}", dir_path, title), component.extension()).await?;
+            write_atomically_async(&path, armored.as_bytes(), component.file_mode()).await?;
+            paths.push(PathBuf::from(path));
+        }
+        Ok(paths)
     }
 
+    // The usage isn't part of any key file, so it is kept next to them in <title>.meta
+    fn metadata_path(dir_path: &str, title: &str) -> String {
+        format!("{}/{}.meta", dir_path, title)
+    }
 
-    pub async fn save_shared_secret(&self, base_path: &str, title: &str) -> Result<(), CryptError> {
-        let dir_path = format!("{}/{}", base_path, title);
-        let dir = std::path::Path::new(&dir_path);
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir).map_err(|_| CryptError::WriteError)?;
+    fn metadata(&self) -> String {
+        format!("usage={}\n", self.usage.name())
+    }
+
+    // Keychains saved before the metadata file existed have no usage recorded and load as Both
+    async fn load_usage(dir_path: &str, title: &str) -> Result<KeyUsage, CryptError> {
+        let metadata = match tokio::fs::read_to_string(Self::metadata_path(dir_path, title)).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(KeyUsage::Both),
+            Err(err) => return Err(err.into()),
+        };
+        metadata.lines()
+            .find_map(|line| line.strip_prefix("usage="))
+            .and_then(|name| KeyUsage::from_name(name.trim()))
+            .ok_or(CryptError::InvalidMessageFormat)
+    }
+
+    fn armored(&self, component: &KeyTypes) -> String {
+        let bytes = match component {
+            KeyTypes::PublicKey => self.public_key.as_ref().expect("Public key is missing").as_bytes(),
+            KeyTypes::SecretKey => self.secret_key.as_ref().expect("Secret key is missing").as_bytes(),
+            KeyTypes::SharedSecret => self.shared_secret.as_ref().expect("Shared secret is missing").as_bytes(),
+            KeyTypes::Ciphertext => self.ciphertext.as_ref().expect("Ciphertext is missing").as_bytes(),
+            KeyTypes::All => unreachable!(),
+        };
+        let (start_label, end_label) = component.labels();
+        format!("{}{}{}", start_label, hex::encode(bytes), end_label)
+    }
+
+    pub async fn rotate_keys(&mut self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        let (pk, sk) = generate_keypair();
+        let (ss, ct) = generate_encapsulation(&pk);
+        self.public_key = Some(pk);
+        self.secret_key = Some(sk);
+        self.shared_secret = Some(ss);
+        self.ciphertext = Some(ct);
+
+        self.save_generation(base_path, title).await
+    }
+
+    pub async fn save_keys(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        self.save_components(base_path, title, &[KeyTypes::PublicKey, KeyTypes::SecretKey]).await
+    }
+
+    pub async fn save_public_key(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        self.save_components(base_path, title, &[KeyTypes::PublicKey]).await
+    }
+
+    pub async fn save_secret_key(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        self.save_components(base_path, title, &[KeyTypes::SecretKey]).await
+    }
+
+    pub async fn save_ciphertext(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        self.save_components(base_path, title, &[KeyTypes::Ciphertext]).await
+    }
+
+    pub async fn save_shared_secret(&self, base_path: &str, title: &str) -> Result<Vec<PathBuf>, CryptError> {
+        self.save_components(base_path, title, &[KeyTypes::SharedSecret]).await
+    }
+
+    pub async fn public_key_from_file(path: PathBuf) -> Result<mceliece8192128::PublicKey, CryptError> {
+        let public_key_bytes = File::load(path, KeyTypes::PublicKey).await?;
+        public_key_from_slice(&public_key_bytes)
+    }
+
+    pub async fn secret_key_from_file(path: PathBuf) -> Result<mceliece8192128::SecretKey, CryptError> {
+        let secret_key_bytes = File::load(path, KeyTypes::SecretKey).await?;
+        secret_key_from_slice(&secret_key_bytes)
+    }
+
+    pub async fn ciphertext_from_file(path: PathBuf) -> Result<mceliece8192128::Ciphertext, CryptError> {
+        let cipher_bytes = File::load(path, KeyTypes::Ciphertext).await?;
+        ciphertext_from_slice(&cipher_bytes)
+    }
+
+    pub async fn shared_secret_from_file(path: PathBuf) -> Result<mceliece8192128::SharedSecret, CryptError> {
+        let shared_secret_bytes = File::load(path, KeyTypes::SharedSecret).await?;
+        shared_secret_from_slice(&shared_secret_bytes)
+    }
+
+    // DER encoded SubjectPublicKeyInfo for tooling that does not understand the hex armor
+    pub fn export_public_spki(&self) -> Result<Vec<u8>, CryptError> {
+        let public_key = self.public_key.as_ref().ok_or(CryptError::MissingPublicKey)?;
+        let spki = SubjectPublicKeyInfoRef {
+            algorithm: AlgorithmIdentifierRef { oid: MCELIECE_8192128_OID, parameters: None },
+            subject_public_key: BitStringRef::from_bytes(public_key.as_bytes()).map_err(|_| CryptError::InvalidParameters)?,
+        };
+        spki.to_der().map_err(|_| CryptError::InvalidParameters)
+    }
+
+    pub fn import_public_spki(&mut self, der: &[u8]) -> Result<(), CryptError> {
+        let spki = SubjectPublicKeyInfoRef::from_der(der).map_err(|_| CryptError::InvalidMessageFormat)?;
+        if spki.algorithm.oid != MCELIECE_8192128_OID || spki.algorithm.parameters.is_some() {
+            return Err(CryptError::WrongKeyType);
         }
+        let bytes = spki.subject_public_key.as_bytes().ok_or(CryptError::InvalidMessageFormat)?;
+        self.import_public_key_bytes(bytes)
+    }
 
-        let shared_secret_path = Keychain::generate_unique_filename(&format!("{}/{}", dir_path, title), "ss");
+    pub fn import_public_key_bytes(&mut self, bytes: &[u8]) -> Result<(), CryptError> {
+        let public_key = public_key_from_slice(bytes)?;
+        self.public_key = Some(public_key);
+        Ok(())
+    }
 
-        fs::write(
-            &shared_secret_path, 
-            format!(
-                "-----BEGIN SHARED SECRET-----\n{}\n-----END SHARED SECRET-----",
-                hex::encode(self.shared_secret.as_ref().expect("Shared secret is missing").as_bytes())
-            )
-        ).map_err(|_| CryptError::WriteError)?;
+    pub fn import_secret_key_bytes(&mut self, bytes: &[u8]) -> Result<(), CryptError> {
+        let secret_key = secret_key_from_slice(bytes)?;
+        self.secret_key = Some(secret_key);
+        Ok(())
+    }
 
+    // Keeps the secret key in the OS credential store instead of a .sec file. Windows Credential Manager caps
+    // secrets at 2560 bytes, which is smaller than a McEliece secret key, so this fails with CryptError::Keyring there
+    #[cfg(feature = "keyring")]
+    pub fn store_secret_in_keyring(&self, service: &str, account: &str) -> Result<(), CryptError> {
+        let secret_key = self.secret_key.as_ref().ok_or(CryptError::MissingSecretKey)?;
+        let entry = keyring::Entry::new(service, account)?;
+        entry.set_secret(secret_key.as_bytes())?;
         Ok(())
     }
 
+    #[cfg(feature = "keyring")]
+    pub fn load_secret_from_keyring(&mut self, service: &str, account: &str) -> Result<mceliece8192128::SecretKey, CryptError> {
+        let entry = keyring::Entry::new(service, account)?;
+        let secret_key = secret_key_from_slice(&entry.get_secret()?)?;
+        self.secret_key = Some(secret_key);
+        Ok(secret_key)
+    }
 
     pub async fn load_public_key(&mut self, path: PathBuf) -> Result<mceliece8192128::PublicKey, CryptError> {
-        let public_key_bytes = File::load(path, KeyTypes::PublicKey).await?;
-        let public_key = PublicKey::from_bytes(&public_key_bytes).unwrap();
+        let public_key = Self::public_key_from_file(path.clone()).await?;
+
+        tracing::debug!("loaded public key from {}", path.display());
+        self.public_key = Some(public_key);
+        Ok(public_key)
+    }
 
-        println!("Successfully loaded public key.\n");
+    // TOFU style pinning: the key is only stored when its fingerprint matches the expected one
+    pub async fn load_public_key_pinned(&mut self, path: PathBuf, expected_fingerprint: &str) -> Result<mceliece8192128::PublicKey, CryptError> {
+        let expected = hex::decode(expected_fingerprint.trim())?;
+        let public_key = Self::public_key_from_file(path.clone()).await?;
+        if !bool::from(public_key_fingerprint(&public_key).ct_eq(&expected)) {
+            tracing::warn!("public key in {} does not match the pinned fingerprint", path.display());
+            return Err(CryptError::FingerprintMismatch);
+        }
+
+        tracing::debug!("loaded pinned public key from {}", path.display());
         self.public_key = Some(public_key);
         Ok(public_key)
     }
 
     pub async fn load_secret_key(&mut self, path: PathBuf) -> Result<mceliece8192128::SecretKey, CryptError> {
-        let secret_key_bytes = File::load(path, KeyTypes::SecretKey).await?;
-        let secret_key: mceliece8192128::SecretKey = SecretKey::from_bytes(&secret_key_bytes).unwrap();
+        let secret_key = Self::secret_key_from_file(path.clone()).await?;
 
-        println!("Successfully loaded secret key.\n");
+        tracing::debug!("loaded secret key from {}", path.display());
         self.secret_key = Some(secret_key);
         Ok(secret_key)
     }
 
     pub async fn load_ciphertext(&mut self, path: PathBuf) -> Result<mceliece8192128::Ciphertext, CryptError> {
-        let cipher_bytes = File::load(path, KeyTypes::Ciphertext).await?;
-        let cipher: mceliece8192128::Ciphertext = Ciphertext::from_bytes(&cipher_bytes).unwrap();
+        let cipher = Self::ciphertext_from_file(path.clone()).await?;
 
-        println!("Successfully loaded ciphertext.\n");
+        tracing::debug!("loaded ciphertext from {}", path.display());
         self.ciphertext = Some(cipher);
         Ok(cipher)
     }
 
     pub async fn load_shared_secret(&mut self, path: PathBuf) -> Result<mceliece8192128::SharedSecret, CryptError> {
-        let shared_secret_bytes = File::load(path, KeyTypes::SharedSecret).await?;
-        let shared_secret: mceliece8192128::SharedSecret = SharedSecret::from_bytes(&shared_secret_bytes).unwrap();
+        let shared_secret = Self::shared_secret_from_file(path.clone()).await?;
 
-        println!("Successfully loaded shared secret.\n");
+        tracing::debug!("loaded shared secret from {}", path.display());
         self.shared_secret = Some(shared_secret);
         Ok(shared_secret)
     }
 
+    pub async fn load_into(&mut self, path: PathBuf, key_type: KeyTypes) -> Result<(), CryptError> {
+        match key_type {
+            KeyTypes::PublicKey => self.load_public_key(path).await.map(|_| ()),
+            KeyTypes::SecretKey => self.load_secret_key(path).await.map(|_| ()),
+            KeyTypes::SharedSecret => self.load_shared_secret(path).await.map(|_| ()),
+            KeyTypes::Ciphertext => self.load_ciphertext(path).await.map(|_| ()),
+            // A single path cannot hold every component, use load_latest for a whole keychain directory
+            KeyTypes::All => Err(CryptError::InvalidParameters),
+        }
+    }
+
     pub async fn get_public_key(&self) -> Result<mceliece8192128::PublicKey, CryptError> {
         let public = self.public_key.unwrap();
         Ok(public)
@@ -385,13 +1070,61 @@ impl Keychain {
         Ok(shared_sec)
     }
 
-    pub fn generate_unique_filename(base_path: &str, extension: &str) -> String {
-        let mut counter = 1;
-        let mut unique_path = format!("{}.{}", base_path, extension);
-        while std::path::Path::new(&unique_path).exists() {
-            unique_path = format!("{}_{}.{}", base_path, counter, extension);
-            counter += 1;
+    pub fn shared_secret_array(&self) -> Result<[u8; SHARED_SECRET_LEN], CryptError> {
+        let shared_secret = self.shared_secret.as_ref().ok_or(CryptError::MissingSharedSecret)?;
+        shared_secret.as_bytes().try_into().map_err(|_| CryptError::InvalidParameters)
+    }
+
+    // Lengths of the stored components, None for the ones that are not set
+    pub fn public_key_len(&self) -> Option<usize> {
+        self.public_key.as_ref().map(|public_key| public_key.as_bytes().len())
+    }
+
+    pub fn secret_key_len(&self) -> Option<usize> {
+        self.secret_key.as_ref().map(|secret_key| secret_key.as_bytes().len())
+    }
+
+    pub fn ciphertext_len(&self) -> Option<usize> {
+        self.ciphertext.as_ref().map(|ciphertext| ciphertext.as_bytes().len())
+    }
+
+    pub fn shared_secret_len(&self) -> Option<usize> {
+        self.shared_secret.as_ref().map(|shared_secret| shared_secret.as_bytes().len())
+    }
+
+    pub fn generate_unique_filename(base_path: &str, extension: &str) -> Result<String, CryptError> {
+        let mut counter = 0;
+        loop {
+            let unique_path = if counter == 0 {
+                format!("{}.{}", base_path, extension)
+            } else {
+                format!("{}_{}.{}", base_path, counter, extension)
+            };
+
+            // Creating the file reserves the name, so concurrent callers never receive the same path
+            match fs::OpenOptions::new().write(true).create_new(true).open(&unique_path) {
+                Ok(_) => return Ok(unique_path),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => counter += 1,
+                Err(err) => return Err(CryptError::Io(err)),
+            }
+        }
+    }
+
+    // Async counterpart of generate_unique_filename, reserving the name without blocking the executor
+    pub async fn generate_unique_filename_async(base_path: &str, extension: &str) -> Result<String, CryptError> {
+        let mut counter = 0;
+        loop {
+            let unique_path = if counter == 0 {
+                format!("{}.{}", base_path, extension)
+            } else {
+                format!("{}_{}.{}", base_path, counter, extension)
+            };
+
+            match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&unique_path).await {
+                Ok(_) => return Ok(unique_path),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => counter += 1,
+                Err(err) => return Err(CryptError::Io(err)),
+            }
         }
-        unique_path
     }
 }
\ No newline at end of file
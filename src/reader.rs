@@ -0,0 +1,123 @@
+use std::io::{self, Read};
+use aes::{cipher::{generic_array::GenericArray, KeyInit}, Aes256};
+use crate::{
+    envelope::{self, HmacVerifier, HMAC_LEN},
+    error::CryptError,
+    keychain::check_distinct_keys,
+};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const BLOCK_LEN: usize = 16;
+
+// Yields the plaintext of an AES envelope (blocks followed by the HMAC tag) as it is read. The last HMAC_LEN
+// bytes are held back as the tag and checked at the end of the input, so plaintext is handed out before the
+// tag is verified and a mismatch only surfaces as an InvalidData error from the final read
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    cipher: Aes256,
+    verifier: Option<HmacVerifier>,
+    pending: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+    // Zeros are held back until a later non-zero byte shows they are not padding
+    pending_zeros: usize,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(inner: R, key: &[u8], hmac_key: &[u8]) -> Result<Self, CryptError> {
+        if key.len() != 32 {
+            return Err(CryptError::InvalidKeyLength { expected: 32, actual: key.len() });
+        }
+        check_distinct_keys(key, hmac_key)?;
+        Ok(Self {
+            inner,
+            cipher: Aes256::new(GenericArray::from_slice(key)),
+            verifier: Some(HmacVerifier::new(hmac_key)?),
+            pending: Vec::new(),
+            plaintext: Vec::new(),
+            position: 0,
+            pending_zeros: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Decrypts the whole blocks in pending, keeping back the last `keep` bytes
+    fn decrypt_pending(&mut self, keep: usize) {
+        let available = self.pending.len().saturating_sub(keep);
+        let len = available - available % BLOCK_LEN;
+        if len == 0 {
+            return;
+        }
+
+        if let Some(verifier) = self.verifier.as_mut() {
+            verifier.update(&self.pending[..len]);
+        }
+        let mut decrypted = vec![0u8; len];
+        envelope::decrypt_blocks_into(&self.cipher, &self.pending[..len], &mut decrypted);
+        self.pending.drain(..len);
+
+        self.plaintext.clear();
+        self.position = 0;
+        match decrypted.iter().rposition(|&byte| byte != 0) {
+            Some(last) => {
+                self.plaintext.resize(self.pending_zeros, 0);
+                self.plaintext.extend_from_slice(&decrypted[..=last]);
+                self.pending_zeros = decrypted.len() - last - 1;
+            }
+            None => self.pending_zeros += decrypted.len(),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let Some(verifier) = self.verifier.take() else {
+            return Ok(());
+        };
+        if self.pending.len() < HMAC_LEN {
+            return Err(invalid_data(CryptError::HmacShortData));
+        }
+        if self.pending.len() != HMAC_LEN {
+            return Err(invalid_data(CryptError::InvalidMessageFormat));
+        }
+        // The zeros still held back are the padding and are dropped
+        verifier.finalize(&self.pending).map_err(invalid_data)
+    }
+}
+
+fn invalid_data(err: CryptError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.plaintext.len() {
+                let len = buf.len().min(self.plaintext.len() - self.position);
+                buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+                self.position += len;
+                return Ok(len);
+            }
+            if self.verifier.is_none() || buf.is_empty() {
+                return Ok(0);
+            }
+
+            let start = self.pending.len();
+            self.pending.resize(start + READ_CHUNK_SIZE, 0);
+            let read = match self.inner.read(&mut self.pending[start..]) {
+                Ok(read) => read,
+                Err(err) => {
+                    self.pending.truncate(start);
+                    return Err(err);
+                }
+            };
+            self.pending.truncate(start + read);
+
+            self.decrypt_pending(HMAC_LEN);
+            if read == 0 && self.position == self.plaintext.len() {
+                self.finish()?;
+            }
+        }
+    }
+}
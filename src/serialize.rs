@@ -0,0 +1,79 @@
+use crate::keychain::{ciphertext_from_slice, public_key_from_slice, secret_key_from_slice, shared_secret_from_slice, KeyUsage};
+use crate::KeychainMceliece as Keychain;
+use pqcrypto_traits::kem::{Ciphertext, PublicKey, SecretKey, SharedSecret};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+// Components are hex strings and absent ones are left out. The secret key and shared secret are only
+// written through Keychain::with_secrets, so serializing a keychain cannot leak them by accident
+#[derive(Serialize, Deserialize)]
+struct KeychainRepr {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shared_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ciphertext: Option<String>,
+    #[serde(default = "default_usage")]
+    usage: KeyUsage,
+}
+
+fn default_usage() -> KeyUsage {
+    KeyUsage::Both
+}
+
+impl KeychainRepr {
+    fn new(keychain: &Keychain, include_secrets: bool) -> Self {
+        Self {
+            public_key: keychain.public_key.as_ref().map(|key| hex::encode(key.as_bytes())),
+            secret_key: keychain.secret_key.as_ref().filter(|_| include_secrets).map(|key| hex::encode(key.as_bytes())),
+            shared_secret: keychain.shared_secret.as_ref().filter(|_| include_secrets).map(|key| hex::encode(key.as_bytes())),
+            ciphertext: keychain.ciphertext.as_ref().map(|key| hex::encode(key.as_bytes())),
+            usage: keychain.usage,
+        }
+    }
+}
+
+pub struct KeychainWithSecrets<'a>(&'a Keychain);
+
+impl Keychain {
+    // Opt-in to serializing the secret key and shared secret as well
+    pub fn with_secrets(&self) -> KeychainWithSecrets<'_> {
+        KeychainWithSecrets(self)
+    }
+}
+
+impl Serialize for Keychain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        KeychainRepr::new(self, false).serialize(serializer)
+    }
+}
+
+impl Serialize for KeychainWithSecrets<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        KeychainRepr::new(self.0, true).serialize(serializer)
+    }
+}
+
+fn decode_component<T, E: de::Error>(hex_value: Option<String>, from_slice: fn(&[u8]) -> Result<T, crate::keychain::CryptError>) -> Result<Option<T>, E> {
+    hex_value
+        .map(|hex_value| {
+            let bytes = hex::decode(hex_value).map_err(E::custom)?;
+            from_slice(&bytes).map_err(E::custom)
+        })
+        .transpose()
+}
+
+impl<'de> Deserialize<'de> for Keychain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = KeychainRepr::deserialize(deserializer)?;
+        Ok(Keychain {
+            public_key: decode_component(repr.public_key, public_key_from_slice)?,
+            secret_key: decode_component(repr.secret_key, secret_key_from_slice)?,
+            shared_secret: decode_component(repr.shared_secret, shared_secret_from_slice)?,
+            ciphertext: decode_component(repr.ciphertext, ciphertext_from_slice)?,
+            usage: repr.usage,
+        })
+    }
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "std")]
+
+use crypt_guard_mceliece::{
+    CipherModeMceliece as CipherMode, DecryptBuilderMceliece as DecryptBuilder,
+    EncryptMceliece as Encrypt, KeychainMceliece as Keychain,
+};
+use pqcrypto_traits::kem::SharedSecret as _;
+use std::{future::Future, thread};
+
+const HMAC_KEY: &[u8] = b"roundtrip hmac key";
+
+// Key generation needs far more stack than the default test thread gets
+fn run<F, Fut>(test: F)
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()>,
+{
+    thread::Builder::new()
+        .stack_size(256 * 1024 * 1024)
+        .spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(test())
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+// Ends on a non-zero byte since the AES path strips its zero padding
+fn known_buffer() -> Vec<u8> {
+    (0..10_000u32).map(|i| (i % 255) as u8 + 1).collect()
+}
+
+#[cfg(feature = "default")]
+#[test]
+fn roundtrip_aes() {
+    run(|| async {
+        let keychain = Keychain::new().unwrap();
+        let shared_secret = keychain.shared_secret.unwrap();
+        let decrypt = DecryptBuilder::new().mode(CipherMode::Aes).build().unwrap();
+
+        let encrypted = Encrypt::new().encrypt_data(&known_buffer(), shared_secret.as_bytes(), HMAC_KEY).await.unwrap();
+        assert_ne!(encrypted, known_buffer());
+        let decrypted = decrypt.decrypt_configured(&encrypted, &shared_secret, HMAC_KEY, None).await.unwrap();
+        assert_eq!(decrypted, known_buffer());
+    });
+}
+
+#[cfg(feature = "xchacha20")]
+#[test]
+fn roundtrip_xchacha20() {
+    use crypt_guard_mceliece::generate_nonce;
+
+    run(|| async {
+        let keychain = Keychain::new().unwrap();
+        let shared_secret = keychain.shared_secret.unwrap();
+        let decrypt = DecryptBuilder::new().mode(CipherMode::XChaCha20).build().unwrap();
+        let nonce = generate_nonce();
+
+        let encrypted = Encrypt::new().encrypt_data_xchacha20(&known_buffer(), shared_secret.as_bytes(), &nonce, HMAC_KEY).await.unwrap();
+        assert_ne!(encrypted, known_buffer());
+        let decrypted = decrypt.decrypt_configured(&encrypted, &shared_secret, HMAC_KEY, Some(&nonce)).await.unwrap();
+        assert_eq!(decrypted, known_buffer());
+    });
+}